@@ -1,63 +1,343 @@
 #![warn(clippy::pedantic)]
 
-use std::{fs, path::Path};
+use crate::error::IndexError;
+use crate::hash::HashAlgorithm;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, fs, path::Path};
 
-fn read_artifacts_file(artifacts_file_path: &Path) -> Vec<(String, String)> {
-    if !artifacts_file_path.exists() {
-        return Vec::new();
+/// The current format of the artifacts index. Indexes from before it was versioned are migrated as they're read.
+pub const INDEX_FORMAT: u64 = 1;
+
+/// One build of an artifact, as kept in a repo's artifacts index.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ArtifactVersion {
+    pub manifest_hash: String,
+    /// When it was added to the repo, in seconds since the Unix epoch.
+    /// Unknown for artifacts added before the index kept a history.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<i64>,
+    /// The version it was built as, if one was given.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+}
+
+/// Which version of an artifact to install, see [`install_artifact_version`](crate::install_artifact_version).
+#[cfg(feature = "decoding")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VersionSelector {
+    /// The most recently built version.
+    Latest,
+    /// The most recent build with this version string.
+    Version(String),
+    /// The build with this manifest hash.
+    ManifestHash(String),
+    /// Whichever build this channel (or tag), such as `stable`, points to.
+    Channel(String),
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+struct Index {
+    format: u64,
+    artifacts: BTreeMap<String, Artifact>,
+}
+
+#[derive(Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Artifact {
+    // Every build, oldest first
+    pub versions: Vec<ArtifactVersion>,
+    // The manifest hash each channel points to, which is always one of `versions`
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub channels: BTreeMap<String, String>,
+}
+
+impl Default for Index {
+    fn default() -> Self {
+        Self {
+            format: INDEX_FORMAT,
+            artifacts: BTreeMap::new(),
+        }
     }
+}
+
+// Only the format is read first, so a newer index fails as unsupported rather than malformed
+#[derive(Deserialize)]
+struct Header {
+    format: u64,
+}
+
+fn malformed(reason: String) -> anyhow::Error {
+    IndexError::Malformed { reason }.into()
+}
 
-    let artifacts_file =
-        fs::read_to_string(artifacts_file_path).expect("Couldn't open artifacts file");
+// Reads and checks the index, migrating it from the old text format if needed. A missing index is empty.
+fn read_index(artifacts_file_path: &Path) -> Result<Index> {
+    let contents = match fs::read_to_string(artifacts_file_path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Index::default()),
+        Err(e) => return Err(e.into()),
+    };
 
-    let mut lines: Vec<(String, String)> = Vec::new();
+    let index = if contents.trim_start().starts_with('{') {
+        let Header { format } =
+            serde_json::from_str(&contents).map_err(|e| malformed(e.to_string()))?;
+        if format != INDEX_FORMAT {
+            return Err(IndexError::UnsupportedFormat { format }.into());
+        }
+        serde_json::from_str(&contents).map_err(|e| malformed(e.to_string()))?
+    } else {
+        migrate_text(&contents)?
+    };
 
-    for line in artifacts_file.lines() {
-        let parts = line.split_once(':').expect("Malformed artifacts file");
-        lines.push((parts.0.to_string(), parts.1.to_string()));
+    validate(&index)?;
+    Ok(index)
+}
+
+// The index used to be a line of `name:hash` per artifact, with only its latest build and no history
+fn migrate_text(contents: &str) -> Result<Index> {
+    let mut index = Index::default();
+
+    for line in contents.lines() {
+        let Some((name, manifest_hash)) = line.split_once(':') else {
+            return Err(malformed(format!("{line:?} isn't an artifact")));
+        };
+
+        index
+            .artifacts
+            .entry(name.to_string())
+            .or_default()
+            .versions
+            .push(ArtifactVersion {
+                manifest_hash: manifest_hash.to_string(),
+                timestamp: None,
+                version: None,
+            });
+    }
+
+    Ok(index)
+}
+
+// Artifact and channel names become file names in the Store, so have to be a single path component
+pub fn check_name(name: &str) -> Result<()> {
+    if name.is_empty() || name == "." || name == ".." || name.contains(['/', '\0']) {
+        return Err(IndexError::InvalidName {
+            name: name.to_string(),
+        }
+        .into());
+    }
+
+    Ok(())
+}
+
+// Checks every rule of the index, both when it's read and before it's written
+fn validate(index: &Index) -> Result<()> {
+    for (name, artifact) in &index.artifacts {
+        check_name(name)?;
+        if artifact.versions.is_empty() {
+            return Err(malformed(format!("{name:?} has no builds")));
+        }
+        for version in &artifact.versions {
+            HashAlgorithm::of(&version.manifest_hash).map_err(|e| malformed(e.to_string()))?;
+        }
+        for (channel, manifest_hash) in &artifact.channels {
+            check_name(channel)?;
+            if !artifact
+                .versions
+                .iter()
+                .any(|version| &version.manifest_hash == manifest_hash)
+            {
+                return Err(malformed(format!(
+                    "Channel {channel:?} of {name:?} points to a build it doesn't list"
+                )));
+            }
+        }
     }
 
-    lines
+    Ok(())
 }
 
+// Replaces the index without ever leaving a partly written one, even if the process dies part way through.
+// Only call it while holding `lock`, so concurrent updates aren't lost.
+#[cfg(feature = "encoding")]
+fn write_index(index: &Index, artifacts_file_path: &Path) -> Result<()> {
+    validate(index)?;
+
+    crate::atomic::write(artifacts_file_path, &serde_json::to_vec_pretty(index)?)
+}
+
+// Locks the index against every other update, until the lock is dropped. Reads don't need it, as writes are atomic.
+#[cfg(feature = "encoding")]
+fn lock(artifacts_file_path: &Path) -> Result<crate::atomic::Lock> {
+    crate::atomic::Lock::exclusive(&artifacts_file_path.with_extension("lock"))
+}
+
+// Every build of an artifact, oldest first
 #[cfg(feature = "decoding")]
-pub fn get_artifact(artifact_name: &String, artifacts_file_path: &Path) -> Option<String> {
-    let artifacts = read_artifacts_file(artifacts_file_path);
+pub fn get_versions(
+    artifact_name: &str,
+    artifacts_file_path: &Path,
+) -> Result<Vec<ArtifactVersion>> {
+    Ok(read_index(artifacts_file_path)?
+        .artifacts
+        .remove(artifact_name)
+        .unwrap_or_default()
+        .versions)
+}
 
-    artifacts
-        .iter()
-        .find(|this_artifact| {
-            dbg!(this_artifact);
-            &this_artifact.0 == artifact_name
-        })
-        .map(|artifact| artifact.1.clone())
+// Every artifact in the index, by name
+#[cfg(feature = "decoding")]
+pub fn get_artifacts(artifacts_file_path: &Path) -> Result<BTreeMap<String, Artifact>> {
+    Ok(read_index(artifacts_file_path)?.artifacts)
 }
 
+// The manifest hash every channel of an artifact points to, by channel
+#[cfg(feature = "decoding")]
+pub fn get_channels(
+    artifact_name: &str,
+    artifacts_file_path: &Path,
+) -> Result<BTreeMap<String, String>> {
+    Ok(read_index(artifacts_file_path)?
+        .artifacts
+        .remove(artifact_name)
+        .unwrap_or_default()
+        .channels)
+}
+
+// The manifest hash of the selected build of an artifact
+#[cfg(feature = "decoding")]
+pub fn get_artifact(
+    artifact_name: &str,
+    selector: &VersionSelector,
+    artifacts_file_path: &Path,
+) -> Result<Option<String>> {
+    let mut artifact = read_index(artifacts_file_path)?
+        .artifacts
+        .remove(artifact_name)
+        .unwrap_or_default();
+    let mut versions = artifact.versions.into_iter().rev();
+
+    let version = match selector {
+        VersionSelector::Latest => versions.next(),
+        VersionSelector::Version(wanted) => {
+            versions.find(|version| version.version.as_ref() == Some(wanted))
+        }
+        VersionSelector::ManifestHash(hash) => {
+            versions.find(|version| &version.manifest_hash == hash)
+        }
+        VersionSelector::Channel(channel) => return Ok(artifact.channels.remove(channel)),
+    };
+
+    Ok(version.map(|version| version.manifest_hash))
+}
+
+// Records a new build of an artifact, keeping every earlier one, and points `channels` at it
+#[cfg(feature = "encoding")]
+pub fn add_artifact(
+    artifact_name: &str,
+    version: ArtifactVersion,
+    channels: &[String],
+    artifacts_file_path: &Path,
+) -> Result<()> {
+    let _lock = lock(artifacts_file_path)?;
+    let mut index = read_index(artifacts_file_path)?;
+    let artifact = index
+        .artifacts
+        .entry(artifact_name.to_string())
+        .or_default();
+
+    for channel in channels {
+        artifact
+            .channels
+            .insert(channel.clone(), version.manifest_hash.clone());
+    }
+    artifact.versions.push(version);
+
+    write_index(&index, artifacts_file_path)
+}
+
+// Points `to_channel` of an artifact wherever `from_channel` points, returning the manifest hash
 #[cfg(feature = "encoding")]
-pub fn add_artifact(artifact_name: String, manifest_hash: String, artifacts_file_path: &Path) {
-    let mut artifacts: Vec<(String, String)> = read_artifacts_file(artifacts_file_path)
-        .iter()
-        .filter(|artifact| artifact.0 != artifact_name)
-        .map(|artifact| (artifact.0.clone(), artifact.1.clone()))
-        .collect();
+pub fn promote(
+    artifact_name: &str,
+    from_channel: &str,
+    to_channel: &str,
+    artifacts_file_path: &Path,
+) -> Result<String> {
+    let _lock = lock(artifacts_file_path)?;
+    let mut index = read_index(artifacts_file_path)?;
+    let unknown = || IndexError::UnknownChannel {
+        artifact: artifact_name.to_string(),
+        channel: from_channel.to_string(),
+    };
+    let artifact = index.artifacts.get_mut(artifact_name).ok_or_else(unknown)?;
+    let manifest_hash = artifact
+        .channels
+        .get(from_channel)
+        .ok_or_else(unknown)?
+        .clone();
 
-    artifacts.push((artifact_name, manifest_hash));
+    artifact
+        .channels
+        .insert(to_channel.to_string(), manifest_hash.clone());
 
-    fs::write(artifacts_file_path, serialize_artifacts(artifacts))
-        .expect("Couldn't write artifacts file");
+    write_index(&index, artifacts_file_path)?;
+    Ok(manifest_hash)
 }
 
+// Removes an artifact and its channels from the index, returning every build it had
 #[cfg(feature = "encoding")]
-fn serialize_artifacts(artifacts: Vec<(String, String)>) -> String {
-    use std::fmt::Write;
+pub fn remove_artifact(
+    artifact_name: &str,
+    artifacts_file_path: &Path,
+) -> Result<Vec<ArtifactVersion>> {
+    let _lock = lock(artifacts_file_path)?;
+    let mut index = read_index(artifacts_file_path)?;
 
-    let mut string = String::new();
+    let artifact =
+        index
+            .artifacts
+            .remove(artifact_name)
+            .ok_or_else(|| IndexError::UnknownArtifact {
+                name: artifact_name.to_string(),
+            })?;
 
-    for artifact in artifacts {
-        writeln!(&mut string, "{}:{}", artifact.0, artifact.1).unwrap();
+    write_index(&index, artifacts_file_path)?;
+    Ok(artifact.versions)
+}
+
+// Moves every build and channel of an artifact to a new name, which mustn't already be taken
+#[cfg(feature = "encoding")]
+pub fn rename_artifact(from_name: &str, to_name: &str, artifacts_file_path: &Path) -> Result<()> {
+    let _lock = lock(artifacts_file_path)?;
+    let mut index = read_index(artifacts_file_path)?;
+
+    if index.artifacts.contains_key(to_name) {
+        return Err(IndexError::ArtifactExists {
+            name: to_name.to_string(),
+        }
+        .into());
     }
+    let artifact =
+        index
+            .artifacts
+            .remove(from_name)
+            .ok_or_else(|| IndexError::UnknownArtifact {
+                name: from_name.to_string(),
+            })?;
+    index.artifacts.insert(to_name.to_string(), artifact);
+
+    write_index(&index, artifacts_file_path)
+}
 
-    string
+// Every manifest hash any build of any artifact has
+#[cfg(feature = "encoding")]
+pub fn all_manifests(artifacts_file_path: &Path) -> Result<std::collections::BTreeSet<String>> {
+    Ok(read_index(artifacts_file_path)?
+        .artifacts
+        .into_values()
+        .flat_map(|artifact| artifact.versions)
+        .map(|version| version.manifest_hash)
+        .collect())
 }
 
 #[cfg(test)]
@@ -66,92 +346,300 @@ mod tests {
 
     use super::*;
 
+    #[cfg(feature = "encoding")]
+    fn version(manifest_hash: &str, timestamp: i64, version: Option<&str>) -> ArtifactVersion {
+        ArtifactVersion {
+            manifest_hash: manifest_hash.to_string(),
+            timestamp: Some(timestamp),
+            version: version.map(str::to_string),
+        }
+    }
+
+    fn index_path(test_name: &str) -> std::path::PathBuf {
+        let path = temp_dir().join(format!("LCAS_test_artifact_{test_name}.test"));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
     #[test]
     #[cfg(all(feature = "encoding", feature = "decoding"))]
     fn simple_all() {
         let artifacts = vec![
-            ("test1".to_string(), 1.to_string()),
-            ("test2".to_string(), 2.to_string()),
-            ("test3".to_string(), 3.to_string()),
+            ("test1", version("1", 10, None)),
+            ("test2", version("2", 20, Some("2.0"))),
+            ("test3", version("3", 30, None)),
         ];
+        let artifact_file_path = index_path("simple_all");
 
-        let artifact_file_path = temp_dir().join("LCAS_test_artifact_simple_all.test");
+        for (artifact_name, version) in &artifacts {
+            add_artifact(artifact_name, version.clone(), &[], &artifact_file_path).unwrap();
+        }
 
-        for (artifact_name, manifest_hash) in &artifacts {
-            add_artifact(
-                artifact_name.to_string(),
-                manifest_hash.to_string(),
-                &artifact_file_path,
+        for (artifact_name, version) in &artifacts {
+            assert_eq!(
+                get_versions(artifact_name, &artifact_file_path).unwrap(),
+                vec![version.clone()]
             );
         }
-
-        assert_eq!(&read_artifacts_file(&artifact_file_path), &artifacts);
-
         assert_eq!(
-            get_artifact(&"test1".to_string(), &artifact_file_path).unwrap(),
+            get_artifact("test1", &VersionSelector::Latest, &artifact_file_path)
+                .unwrap()
+                .unwrap(),
             1.to_string()
         );
     }
 
     #[test]
-    #[cfg(all(feature = "encoding", feature = "decoding"))]
+    #[cfg(feature = "decoding")]
     fn get_artifact_not_found() {
-        let artifact_file_path = temp_dir().join("LCAS_test_artifact_not_found.test");
+        let artifact_file_path = index_path("not_found");
+        assert_eq!(
+            get_artifact("nonexistent", &VersionSelector::Latest, &artifact_file_path).unwrap(),
+            None
+        );
         // Ensure file is empty
         fs::write(&artifact_file_path, "").unwrap();
         assert_eq!(
-            get_artifact(&"nonexistent".to_string(), &artifact_file_path),
+            get_artifact("nonexistent", &VersionSelector::Latest, &artifact_file_path).unwrap(),
             None
         );
     }
 
     #[test]
-    #[cfg(feature = "encoding")]
-    fn add_artifact_overwrites_existing() {
-        let artifact_file_path = temp_dir().join("LCAS_test_artifact_overwrite.test");
+    #[cfg(all(feature = "encoding", feature = "decoding"))]
+    fn add_artifact_keeps_history() {
+        let artifact_file_path = index_path("history");
+        let add = |name, version| add_artifact(name, version, &[], &artifact_file_path).unwrap();
+        add("artifact", version("1", 1, Some("1.0")));
+        add("other", version("2", 2, None));
+        add("artifact", version("3", 3, Some("1.1")));
+
+        assert_eq!(
+            get_versions("artifact", &artifact_file_path).unwrap(),
+            vec![version("1", 1, Some("1.0")), version("3", 3, Some("1.1"))]
+        );
+        let get = |selector| {
+            get_artifact("artifact", &selector, &artifact_file_path)
+                .unwrap()
+                .unwrap_or_default()
+        };
+        assert_eq!(get(VersionSelector::Latest), "3");
+        assert_eq!(get(VersionSelector::Version("1.0".to_string())), "1");
+        assert_eq!(get(VersionSelector::ManifestHash("1".to_string())), "1");
+        // Another artifact's build isn't a version of this one
+        assert_eq!(get(VersionSelector::ManifestHash("2".to_string())), "");
+        assert_eq!(get(VersionSelector::Version("2.0".to_string())), "");
+    }
+
+    #[test]
+    #[cfg(all(feature = "encoding", feature = "decoding"))]
+    fn promote_moves_channels() {
+        let artifact_file_path = index_path("channels");
+        let nightly = ["nightly".to_string()];
         add_artifact(
-            "artifact".to_string(),
-            "hash1".to_string(),
+            "artifact",
+            version("1", 1, None),
+            &nightly,
             &artifact_file_path,
-        );
+        )
+        .unwrap();
+        promote("artifact", "nightly", "stable", &artifact_file_path).unwrap();
         add_artifact(
-            "artifact".to_string(),
-            "hash2".to_string(),
+            "artifact",
+            version("2", 2, None),
+            &nightly,
             &artifact_file_path,
+        )
+        .unwrap();
+
+        assert_eq!(
+            get_channels("artifact", &artifact_file_path).unwrap(),
+            BTreeMap::from([
+                ("nightly".to_string(), "2".to_string()),
+                ("stable".to_string(), "1".to_string()),
+            ])
+        );
+        assert!(
+            get_channels("other", &artifact_file_path)
+                .unwrap()
+                .is_empty()
+        );
+        let get = |channel: &str| {
+            get_artifact(
+                "artifact",
+                &VersionSelector::Channel(channel.to_string()),
+                &artifact_file_path,
+            )
+            .unwrap()
+        };
+        assert_eq!(get("stable").unwrap(), "1");
+        assert_eq!(get("beta"), None);
+        // Channels aren't builds
+        assert_eq!(
+            get_versions("artifact", &artifact_file_path).unwrap().len(),
+            2
+        );
+
+        let error = promote("artifact", "beta", "stable", &artifact_file_path).unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<IndexError>(),
+            Some(&IndexError::UnknownChannel {
+                artifact: "artifact".to_string(),
+                channel: "beta".to_string(),
+            })
         );
-        let artifacts = read_artifacts_file(&artifact_file_path);
-        assert_eq!(artifacts.len(), 1);
-        assert_eq!(artifacts[0], ("artifact".to_string(), "hash2".to_string()));
+        let error = promote("artifact", "nightly", "a/b", &artifact_file_path).unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<IndexError>(),
+            Some(IndexError::InvalidName { .. })
+        ));
     }
 
     #[test]
-    fn read_artifacts_file_nonexistent() {
-        let artifact_file_path = temp_dir().join("LCAS_test_artifact_nonexistent.test");
-        // Ensure file does not exist
-        let _ = fs::remove_file(&artifact_file_path);
-        let artifacts = read_artifacts_file(&artifact_file_path);
-        assert!(artifacts.is_empty());
+    #[cfg(all(feature = "encoding", feature = "decoding"))]
+    fn remove_and_rename_artifacts() {
+        let artifact_file_path = index_path("remove_rename");
+        let stable = ["stable".to_string()];
+        add_artifact("a", version("1", 1, None), &stable, &artifact_file_path).unwrap();
+        add_artifact("a", version("2", 2, None), &[], &artifact_file_path).unwrap();
+        add_artifact("b", version("2", 3, None), &[], &artifact_file_path).unwrap();
+
+        rename_artifact("a", "c", &artifact_file_path).unwrap();
+        assert!(get_versions("a", &artifact_file_path).unwrap().is_empty());
+        assert_eq!(get_versions("c", &artifact_file_path).unwrap().len(), 2);
+        assert_eq!(
+            get_channels("c", &artifact_file_path).unwrap()["stable"],
+            "1"
+        );
+
+        let error = rename_artifact("c", "b", &artifact_file_path).unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<IndexError>(),
+            Some(&IndexError::ArtifactExists {
+                name: "b".to_string()
+            })
+        );
+        assert!(rename_artifact("c", "..", &artifact_file_path).is_err());
+
+        assert_eq!(
+            remove_artifact("c", &artifact_file_path).unwrap(),
+            vec![version("1", 1, None), version("2", 2, None)]
+        );
+        let error = remove_artifact("c", &artifact_file_path).unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<IndexError>(),
+            Some(&IndexError::UnknownArtifact {
+                name: "c".to_string()
+            })
+        );
+        assert_eq!(
+            all_manifests(&artifact_file_path).unwrap(),
+            std::collections::BTreeSet::from(["2".to_string()])
+        );
     }
 
     #[test]
-    #[should_panic(expected = "Malformed artifacts file")]
-    fn read_artifacts_file_malformed() {
-        let artifact_file_path = temp_dir().join("LCAS_test_artifact_malformed.test");
-        fs::write(&artifact_file_path, "bad_line_without_colon\n").unwrap();
-        let _ = read_artifacts_file(&artifact_file_path);
+    fn migrate_text_index() {
+        let artifact_file_path = index_path("migrate_text");
+        fs::write(&artifact_file_path, "first:1\nsecond:2\n").unwrap();
+
+        let unversioned = |manifest_hash: &str| Artifact {
+            versions: vec![ArtifactVersion {
+                manifest_hash: manifest_hash.to_string(),
+                timestamp: None,
+                version: None,
+            }],
+            channels: BTreeMap::new(),
+        };
+        let mut expected = Index::default();
+        expected
+            .artifacts
+            .insert("first".to_string(), unversioned("1"));
+        expected
+            .artifacts
+            .insert("second".to_string(), unversioned("2"));
+        assert_eq!(read_index(&artifact_file_path).unwrap(), expected);
+
+        // The next update writes it in the current format
+        #[cfg(feature = "encoding")]
+        {
+            fs::write(&artifact_file_path, "first:1\nsecond:2\n").unwrap();
+            add_artifact("second", version("4", 40, None), &[], &artifact_file_path).unwrap();
+            let contents = fs::read_to_string(&artifact_file_path).unwrap();
+            assert!(contents.starts_with('{'));
+            expected
+                .artifacts
+                .get_mut("second")
+                .unwrap()
+                .versions
+                .push(version("4", 40, None));
+            assert_eq!(read_index(&artifact_file_path).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn read_index_nonexistent() {
+        let artifact_file_path = index_path("nonexistent");
+        assert_eq!(read_index(&artifact_file_path).unwrap(), Index::default());
+    }
+
+    #[test]
+    fn read_index_malformed() {
+        let artifact_file_path = index_path("malformed");
+        for contents in [
+            "bad_line_without_colon\n",
+            "name:not_a_hash\n",
+            // Builds and channels were never kept in the text index
+            "name:1:20\n",
+            "@stable:name:1\n",
+            "a/b:1\n",
+            "{\"format\": 1, \"artifacts\": {\"name\": {\"versions\": []}}}",
+            "{\"format\": 1",
+        ] {
+            fs::write(&artifact_file_path, contents).unwrap();
+            let error = read_index(&artifact_file_path).unwrap_err();
+            assert!(
+                matches!(
+                    error.downcast_ref::<IndexError>(),
+                    Some(IndexError::Malformed { .. } | IndexError::InvalidName { .. })
+                ),
+                "{contents:?} gave {error}"
+            );
+        }
+    }
+
+    #[test]
+    fn read_index_unsupported_format() {
+        let artifact_file_path = index_path("unsupported");
+        fs::write(&artifact_file_path, "{\"format\": 2, \"artifacts\": []}").unwrap();
+        let error = read_index(&artifact_file_path).unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<IndexError>(),
+            Some(&IndexError::UnsupportedFormat { format: 2 })
+        );
     }
 
     #[test]
     #[cfg(feature = "encoding")]
-    fn simple_serialize_artifacts() {
-        let artifacts = vec![
-            ("test1".to_string(), 1.to_string()),
-            ("test2".to_string(), 2.to_string()),
-            ("test3".to_string(), 3.to_string()),
-        ];
+    fn write_index_is_validated() {
+        let artifact_file_path = index_path("write_validated");
+        add_artifact("artifact", version("1", 1, None), &[], &artifact_file_path).unwrap();
+        let before = fs::read(&artifact_file_path).unwrap();
+
+        assert!(add_artifact("..", version("2", 2, None), &[], &artifact_file_path).is_err());
+        assert!(add_artifact("artifact", version("x", 2, None), &[], &artifact_file_path).is_err());
+        // Nothing was written, not even a temporary file
+        assert_eq!(fs::read(&artifact_file_path).unwrap(), before);
         assert_eq!(
-            serialize_artifacts(artifacts),
-            "test1:1\ntest2:2\ntest3:3\n"
+            fs::read_dir(temp_dir())
+                .unwrap()
+                .flatten()
+                .filter(|f| f
+                    .file_name()
+                    .to_string_lossy()
+                    .starts_with(".LCAS_test_artifact_write_validated"))
+                .count(),
+            0
         );
     }
 }
@@ -0,0 +1,181 @@
+#![warn(clippy::pedantic)]
+
+use anyhow::Result;
+use std::fs::{self, File};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// An exclusive advisory lock on a lock file, held until it's dropped.
+///
+/// Builders sharing a repo take it around every update of the artifacts index, so none of them can overwrite
+/// another's changes. It's an `flock`, which Linux also takes over NFS.
+pub struct Lock {
+    _file: File,
+}
+
+impl Lock {
+    // Waits until nothing else holds the lock, creating the lock file if needed
+    pub fn exclusive(path: &Path) -> Result<Self> {
+        let file = fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(path)?;
+        rustix::fs::flock(&file, rustix::fs::FlockOperation::LockExclusive)?;
+
+        Ok(Self { _file: file })
+    }
+}
+
+// A name next to `path` that no other process, thread or host is writing to
+fn temp_path(path: &Path) -> PathBuf {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+    let nanos = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |time| time.subsec_nanos());
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy())
+        .unwrap_or_default();
+
+    path.with_file_name(format!(
+        ".{name}.tmp_{}_{}_{nanos}",
+        std::process::id(),
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    ))
+}
+
+// Writes all of `contents` to a temporary file next to `path`, and flushes it to disk
+fn write_temp(path: &Path, contents: &[u8]) -> Result<PathBuf> {
+    let tmp_path = temp_path(path);
+    let mut file = File::create_new(&tmp_path)?;
+
+    if let Err(e) = file.write_all(contents).and_then(|()| file.sync_all()) {
+        let _ = fs::remove_file(&tmp_path);
+        return Err(e.into());
+    }
+
+    Ok(tmp_path)
+}
+
+// Flushes the directory `path` is in, so a rename or link into it survives a crash as well as the file's contents
+fn sync_parent(path: &Path) -> Result<()> {
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    File::open(parent)?.sync_all()?;
+
+    Ok(())
+}
+
+// Replaces `path` with `contents`, so readers only ever see all of the old file or all of the new one
+pub fn write(path: &Path, contents: &[u8]) -> Result<()> {
+    let tmp_path = write_temp(path, contents)?;
+
+    if let Err(e) = fs::rename(&tmp_path, path) {
+        let _ = fs::remove_file(&tmp_path);
+        return Err(e.into());
+    }
+
+    sync_parent(path)
+}
+
+// Creates `path` with `contents` unless it already exists, returning whether it was created.
+// For content addressed files, which only need writing once. A crash can't leave a partial one to be reused later.
+pub fn write_new(path: &Path, contents: &[u8]) -> Result<bool> {
+    let tmp_path = write_temp(path, contents)?;
+
+    // Linking fails if `path` exists, even over NFS, where checking first and then renaming would race
+    let created = match fs::hard_link(&tmp_path, path) {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == ErrorKind::AlreadyExists => Ok(false),
+        // Not every filesystem has hard links. Renaming over an existing file is still safe, as it's named by its
+        // content, so it can only be replaced with the same thing.
+        Err(e)
+            if matches!(
+                e.kind(),
+                ErrorKind::Unsupported | ErrorKind::PermissionDenied
+            ) =>
+        {
+            fs::rename(&tmp_path, path).map(|()| true)
+        }
+        Err(e) => Err(e),
+    };
+
+    let _ = fs::remove_file(&tmp_path);
+    if created? {
+        sync_parent(path)?;
+        return Ok(true);
+    }
+
+    Ok(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env::temp_dir;
+
+    // Temporary files left next to the test's files
+    fn leftovers(dir: &Path) -> usize {
+        fs::read_dir(dir)
+            .unwrap()
+            .flatten()
+            .filter(|f| f.file_name().to_string_lossy().starts_with('.'))
+            .count()
+    }
+
+    #[test]
+    fn write_new_only_creates_once() {
+        let dir = temp_dir().join("lcas_atomic_write_new_test");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("file");
+
+        assert!(write_new(&path, b"first").unwrap());
+        assert!(!write_new(&path, b"second").unwrap());
+        assert_eq!(fs::read(&path).unwrap(), b"first");
+        assert_eq!(leftovers(&dir), 0);
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn write_replaces() {
+        let dir = temp_dir().join("lcas_atomic_write_test");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("file");
+
+        write(&path, b"first").unwrap();
+        write(&path, b"second").unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"second");
+        assert_eq!(leftovers(&dir), 0);
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn lock_is_exclusive() {
+        use std::sync::mpsc;
+        use std::time::Duration;
+
+        let path = temp_dir().join("lcas_atomic_lock_test.lock");
+        let lock = Lock::exclusive(&path).unwrap();
+
+        let (sender, receiver) = mpsc::channel();
+        let waiter = std::thread::spawn(move || {
+            let _lock = Lock::exclusive(&path).unwrap();
+            sender.send(()).unwrap();
+        });
+
+        // The other lock can't be taken until this one is dropped
+        assert!(receiver.recv_timeout(Duration::from_millis(200)).is_err());
+        drop(lock);
+        receiver.recv_timeout(Duration::from_secs(10)).unwrap();
+        waiter.join().unwrap();
+    }
+}
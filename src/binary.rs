@@ -0,0 +1,203 @@
+#![warn(clippy::pedantic)]
+
+// A simple binary encoding, where every string and list is prefixed with its length, and every integer is a fixed
+// width. Each value has exactly one encoding, and no two values share one, so it's safe to hash.
+
+use anyhow::{Result, bail};
+
+#[derive(Default)]
+pub struct Encoder {
+    bytes: Vec<u8>,
+}
+
+impl Encoder {
+    pub fn u8(&mut self, value: u8) {
+        self.bytes.push(value);
+    }
+
+    pub fn u32(&mut self, value: u32) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn u64(&mut self, value: u64) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn i64(&mut self, value: i64) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    // Lengths are written as `u64`, so they're the same on every platform
+    pub fn len(&mut self, len: usize) {
+        self.u64(len as u64);
+    }
+
+    pub fn bytes(&mut self, value: &[u8]) {
+        self.len(value.len());
+        self.bytes.extend_from_slice(value);
+    }
+
+    pub fn str(&mut self, value: &str) {
+        self.bytes(value.as_bytes());
+    }
+
+    // Optional values are prefixed with whether they're present
+    pub fn optional_str(&mut self, value: Option<&str>) {
+        self.u8(value.is_some().into());
+        if let Some(value) = value {
+            self.str(value);
+        }
+    }
+
+    pub fn optional_i64(&mut self, value: Option<i64>) {
+        self.u8(value.is_some().into());
+        if let Some(value) = value {
+            self.i64(value);
+        }
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        self.bytes
+    }
+}
+
+// Reads values back in the order they were encoded. Malformed input is an error, never a panic or a huge allocation.
+pub struct Decoder<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Decoder<'a> {
+    pub fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes }
+    }
+
+    fn take<const N: usize>(&mut self) -> Result<[u8; N]> {
+        let Some((value, rest)) = self.bytes.split_first_chunk() else {
+            bail!("Unexpected end of input");
+        };
+
+        self.bytes = rest;
+        Ok(*value)
+    }
+
+    pub fn u8(&mut self) -> Result<u8> {
+        Ok(u8::from_le_bytes(self.take()?))
+    }
+
+    pub fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.take()?))
+    }
+
+    pub fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_le_bytes(self.take()?))
+    }
+
+    pub fn i64(&mut self) -> Result<i64> {
+        Ok(i64::from_le_bytes(self.take()?))
+    }
+
+    pub fn len(&mut self) -> Result<usize> {
+        Ok(usize::try_from(self.u64()?)?)
+    }
+
+    pub fn bytes(&mut self) -> Result<Vec<u8>> {
+        let len = self.len()?;
+        if len > self.bytes.len() {
+            bail!("Unexpected end of input");
+        }
+
+        let (value, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Ok(value.to_vec())
+    }
+
+    pub fn str(&mut self) -> Result<String> {
+        Ok(String::from_utf8(self.bytes()?)?)
+    }
+
+    // Reads whether an optional value is present, rejecting anything but 0 or 1
+    fn present(&mut self) -> Result<bool> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            tag => bail!("Invalid optional value tag {tag}"),
+        }
+    }
+
+    pub fn optional_str(&mut self) -> Result<Option<String>> {
+        self.present()?.then(|| self.str()).transpose()
+    }
+
+    pub fn optional_i64(&mut self) -> Result<Option<i64>> {
+        self.present()?.then(|| self.i64()).transpose()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
+    // Rejects anything left over, so every input decodes to at most one value
+    pub fn finish(self) -> Result<()> {
+        if !self.is_empty() {
+            bail!("Unexpected trailing bytes");
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strings_are_length_prefixed() {
+        let encode = |first: &str, second: &str| {
+            let mut encoder = Encoder::default();
+            encoder.str(first);
+            encoder.str(second);
+            encoder.finish()
+        };
+
+        assert_ne!(encode("a1", "23"), encode("a", "123"));
+        assert_eq!(
+            encode("a", ""),
+            vec![1, 0, 0, 0, 0, 0, 0, 0, b'a', 0, 0, 0, 0, 0, 0, 0, 0]
+        );
+    }
+
+    #[test]
+    fn decoder_reads_what_was_encoded() {
+        let mut encoder = Encoder::default();
+        encoder.u8(1);
+        encoder.u32(2);
+        encoder.i64(-3);
+        encoder.str("four");
+        encoder.optional_str(None);
+        encoder.optional_i64(Some(5));
+        let bytes = encoder.finish();
+
+        let mut decoder = Decoder::new(&bytes);
+        assert_eq!(decoder.u8().unwrap(), 1);
+        assert_eq!(decoder.u32().unwrap(), 2);
+        assert_eq!(decoder.i64().unwrap(), -3);
+        assert_eq!(decoder.str().unwrap(), "four");
+        assert_eq!(decoder.optional_str().unwrap(), None);
+        assert_eq!(decoder.optional_i64().unwrap(), Some(5));
+        assert!(decoder.finish().is_ok());
+    }
+
+    #[test]
+    fn decoder_rejects_truncated_input() {
+        let mut encoder = Encoder::default();
+        encoder.str("truncated");
+        let bytes = encoder.finish();
+
+        assert!(Decoder::new(&bytes[..bytes.len() - 1]).str().is_err());
+        assert!(Decoder::new(&bytes[..4]).len().is_err());
+        // A length far larger than the input
+        assert!(Decoder::new(&[0xff; 8]).bytes().is_err());
+        assert!(Decoder::new(&bytes).finish().is_err());
+        assert!(Decoder::new(&[2]).optional_str().is_err());
+    }
+}
@@ -0,0 +1,198 @@
+#![warn(clippy::pedantic)]
+
+// Content-defined chunking, in the style of FastCDC.
+// Boundaries are picked by a gear rolling hash over the content, so an edit only moves the boundaries near it,
+// and every other chunk of the file keeps its hash.
+
+use std::io::{self, Read};
+
+/// Files (or remainders) smaller than this are never split.
+pub const MIN_SIZE: usize = 64 * 1024;
+/// The size chunks are normalised towards.
+pub const AVG_SIZE: usize = 256 * 1024;
+/// No chunk is ever larger than this.
+pub const MAX_SIZE: usize = 1024 * 1024;
+
+// Stricter mask used before `AVG_SIZE` is reached, and a looser one after, to keep chunk sizes close to the average.
+// The top bits of the gear hash are used, as they depend on the last 64 bytes rather than only the last few.
+const MASK_SMALL: u64 = u64::MAX << (64 - (AVG_SIZE.trailing_zeros() + 2));
+const MASK_LARGE: u64 = u64::MAX << (64 - (AVG_SIZE.trailing_zeros() - 2));
+
+// Generated with splitmix64, so the table (and therefore every chunk boundary) is stable across builds.
+const GEAR: [u64; 256] = {
+    let mut table = [0u64; 256];
+    let mut state: u64 = 0;
+    let mut i = 0;
+
+    while i < table.len() {
+        state = state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+
+    table
+};
+
+// Finds the length of the first chunk in `data`
+fn cut_point(data: &[u8]) -> usize {
+    if data.len() <= MIN_SIZE {
+        return data.len();
+    }
+
+    let normal = data.len().min(AVG_SIZE);
+    let max = data.len().min(MAX_SIZE);
+    let mut hash = 0u64;
+
+    for (i, byte) in data.iter().enumerate().take(max).skip(MIN_SIZE) {
+        hash = (hash << 1).wrapping_add(GEAR[*byte as usize]);
+
+        let mask = if i < normal { MASK_SMALL } else { MASK_LARGE };
+        if hash & mask == 0 {
+            return i + 1;
+        }
+    }
+
+    max
+}
+
+/// Splits what's read from `reader` into content-defined chunks, one at a time.
+///
+/// Only holds up to `MAX_SIZE` bytes ahead of the current chunk, as no cut point can be further away than that,
+/// so the chunks are the same as if the whole input had been split at once, however large it is.
+pub struct Chunker<R> {
+    reader: R,
+    buffer: Vec<u8>,
+    eof: bool,
+}
+
+impl<R: Read> Chunker<R> {
+    pub fn new(reader: R) -> Self {
+        Self {
+            reader,
+            buffer: Vec::with_capacity(MAX_SIZE),
+            eof: false,
+        }
+    }
+
+    // Reads the next chunk, or `None` once everything has been read. Empty input produces no chunks.
+    pub fn next_chunk(&mut self) -> io::Result<Option<Vec<u8>>> {
+        while !self.eof && self.buffer.len() < MAX_SIZE {
+            let wanted = MAX_SIZE - self.buffer.len();
+            let read = (&mut self.reader)
+                .take(wanted as u64)
+                .read_to_end(&mut self.buffer)?;
+            self.eof = read < wanted;
+        }
+
+        if self.buffer.is_empty() {
+            return Ok(None);
+        }
+
+        let cut = cut_point(&self.buffer);
+        Ok(Some(self.buffer.drain(..cut).collect()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Splits all of `data` at once
+    fn chunk(data: &[u8]) -> Vec<Vec<u8>> {
+        let mut chunker = Chunker::new(data);
+        let mut chunks = Vec::new();
+        while let Some(chunk) = chunker.next_chunk().unwrap() {
+            chunks.push(chunk);
+        }
+        chunks
+    }
+
+    // Deterministic filler data, so boundaries are the same on every run
+    fn test_data(len: usize, seed: u64) -> Vec<u8> {
+        let mut state = seed;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state.to_le_bytes()[0]
+            })
+            .collect()
+    }
+
+    #[test]
+    fn small_input_is_one_chunk() {
+        let data = test_data(1000, 1);
+        assert_eq!(chunk(&data), vec![data]);
+    }
+
+    #[test]
+    fn empty_input_has_no_chunks() {
+        assert!(chunk(&[]).is_empty());
+    }
+
+    #[test]
+    fn chunks_reassemble_to_input() {
+        let data = test_data(4 * 1024 * 1024, 2);
+        let chunks = chunk(&data);
+
+        assert!(chunks.len() > 1);
+        assert_eq!(chunks.concat(), data);
+    }
+
+    #[test]
+    fn chunk_sizes_within_bounds() {
+        let data = test_data(8 * 1024 * 1024, 3);
+        let chunks = chunk(&data);
+
+        for piece in &chunks[..chunks.len() - 1] {
+            assert!(piece.len() >= MIN_SIZE);
+            assert!(piece.len() <= MAX_SIZE);
+        }
+    }
+
+    #[test]
+    fn edit_only_changes_nearby_chunks() {
+        let original = test_data(4 * 1024 * 1024, 4);
+        let mut edited = original.clone();
+        edited[2 * 1024 * 1024] ^= 0xFF;
+
+        let original_chunks = chunk(&original);
+        let edited_chunks = chunk(&edited);
+
+        let changed = edited_chunks
+            .iter()
+            .filter(|piece| !original_chunks.contains(piece))
+            .count();
+
+        assert!(changed <= 2, "{changed} chunks changed");
+    }
+
+    // Hands out a few bytes at a time, like a slow pipe
+    struct Trickle<'a>(&'a [u8]);
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let len = buf.len().min(self.0.len()).min(1000);
+            buf[..len].copy_from_slice(&self.0[..len]);
+            self.0 = &self.0[len..];
+            Ok(len)
+        }
+    }
+
+    #[test]
+    fn chunks_dont_depend_on_reads() {
+        let data = test_data(4 * 1024 * 1024 + 123, 5);
+        let mut chunker = Chunker::new(Trickle(&data));
+        let mut chunks = Vec::new();
+        while let Some(chunk) = chunker.next_chunk().unwrap() {
+            assert!(chunker.buffer.len() <= MAX_SIZE);
+            chunks.push(chunk);
+        }
+
+        assert_eq!(chunks, chunk(&data));
+    }
+}
@@ -2,7 +2,7 @@
 
 // Compresses with ZSTD
 #[cfg(feature = "encoding")]
-pub fn compress_file(input: &Vec<u8>, level: i32) -> Vec<u8> {
+pub fn compress_file(input: &[u8], level: i32) -> Vec<u8> {
     use std::io;
 
     let mut buf = Vec::new();
@@ -15,21 +15,19 @@ pub fn compress_file(input: &Vec<u8>, level: i32) -> Vec<u8> {
     buf
 }
 
-// Decompresses with ZSTD
-#[cfg(feature = "decoding")]
-pub fn decompress_file(input: &mut Vec<u8>) -> Vec<u8> {
-    use std::io::Read;
-
-    let mut cursor = std::io::Cursor::new(input);
-    let mut decoder = zstd::stream::Decoder::new(&mut cursor).unwrap();
-    let mut buf = Vec::new();
-    decoder.read_to_end(&mut buf).unwrap();
-
-    buf
+// Decompresses with ZSTD, failing rather than panicking on malformed input
+#[cfg(any(feature = "decoding", feature = "encoding"))]
+pub fn try_decompress(input: &[u8]) -> anyhow::Result<Vec<u8>> {
+    Ok(zstd::stream::decode_all(input)?)
 }
 
+/// Every ZSTD frame starts with this.
+#[cfg(any(feature = "decoding", feature = "encoding"))]
+pub const MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
 #[cfg(test)]
 mod tests {
+    #[cfg(all(feature = "encoding", feature = "decoding"))]
     use super::*;
 
     #[cfg(all(feature = "encoding", feature = "decoding"))]
@@ -37,7 +35,7 @@ mod tests {
     fn same_as_initial() {
         let original = vec![1, 2, 3, 4, 5];
         let compressed = compress_file(&original, 3);
-        let decompressed = decompress_file(&mut compressed.clone());
+        let decompressed = try_decompress(&compressed).unwrap();
         assert_eq!(original, decompressed);
     }
 }
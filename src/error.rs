@@ -0,0 +1,69 @@
+#![warn(clippy::pedantic)]
+
+use std::fmt;
+
+/// Errors from `install_artifact` that callers may want to handle, rather than only report.
+///
+/// These are returned inside an [`anyhow::Error`], and can be found with `downcast_ref`.
+#[derive(Debug, PartialEq, Eq)]
+pub enum InstallError {
+    /// The artifact has a device node at `path`, which can only be created as root.
+    Unprivileged { path: String },
+    /// The manifest was written in a format this version doesn't know, most likely by a newer version.
+    UnsupportedManifestFormat { format: u64 },
+}
+
+impl fmt::Display for InstallError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Unprivileged { path } => {
+                write!(f, "Device node {path:?} can only be installed as root")
+            }
+            Self::UnsupportedManifestFormat { format } => {
+                write!(f, "Unsupported manifest format {format}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for InstallError {}
+
+/// Errors from reading or updating a repo's artifacts index, such as from `build` or `promote`.
+///
+/// These are returned inside an [`anyhow::Error`], and can be found with `downcast_ref`.
+#[derive(Debug, PartialEq, Eq)]
+pub enum IndexError {
+    /// The index couldn't be parsed, or breaks one of its rules, such as a channel pointing to a build it doesn't list.
+    Malformed { reason: String },
+    /// The index was written in a format this version doesn't know, most likely by a newer version.
+    UnsupportedFormat { format: u64 },
+    /// An artifact or channel name that can't be used, as it's empty or isn't a single path component.
+    InvalidName { name: String },
+    /// The artifact has no channel of this name.
+    UnknownChannel { artifact: String, channel: String },
+    /// No artifact of this name is in the index.
+    UnknownArtifact { name: String },
+    /// An artifact of this name is already in the index, so another can't be renamed to it.
+    ArtifactExists { name: String },
+}
+
+impl fmt::Display for IndexError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Malformed { reason } => write!(f, "Malformed artifacts index: {reason}"),
+            Self::UnsupportedFormat { format } => {
+                write!(f, "Unsupported artifacts index format {format}")
+            }
+            Self::InvalidName { name } => {
+                write!(f, "{name:?} isn't a valid artifact or channel name")
+            }
+            Self::UnknownChannel { artifact, channel } => {
+                write!(f, "{artifact:?} has no channel {channel:?}")
+            }
+            Self::UnknownArtifact { name } => write!(f, "No artifact is named {name:?}"),
+            Self::ArtifactExists { name } => write!(f, "An artifact is already named {name:?}"),
+        }
+    }
+}
+
+impl std::error::Error for IndexError {}
@@ -1,71 +1,359 @@
 #![warn(clippy::pedantic)]
 
-use xxhash_rust::xxh3::xxh3_64;
+use crate::manifest::Manifest;
+use anyhow::{Result, bail};
+use sha2::{Digest, Sha256};
+use xxhash_rust::xxh3::Xxh3;
 
-// Hashes with xxh3
+/// The hash algorithm a repo names its chunks and manifests with, picked when the repo is created.
+///
+/// `Xxh3` hashes are bare numbers, as they have always been. Every other algorithm prefixes its hashes with its name
+/// (e.g. `blake3-af1349b9...`), so a Store can verify content from any repo without knowing how it was created.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum HashAlgorithm {
+    /// Fast, but not collision resistant. Only suitable when everyone who can write to the repo is trusted.
+    #[default]
+    Xxh3,
+    Blake3,
+    Sha256,
+}
+
+impl HashAlgorithm {
+    #[must_use]
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Xxh3 => "xxh3",
+            Self::Blake3 => "blake3",
+            Self::Sha256 => "sha256",
+        }
+    }
+
+    /// Parses an algorithm from the name returned by [`HashAlgorithm::name`].
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the name isn't a known algorithm.
+    pub fn from_name(name: &str) -> Result<Self> {
+        match name {
+            "xxh3" => Ok(Self::Xxh3),
+            "blake3" => Ok(Self::Blake3),
+            "sha256" => Ok(Self::Sha256),
+            _ => bail!("Unknown hash algorithm {name:?}"),
+        }
+    }
+
+    // Finds the algorithm a chunk or manifest hash was created with, rejecting anything that isn't a well-formed hash
+    pub(crate) fn of(hash: &str) -> Result<Self> {
+        let algorithm = match hash.split_once('-') {
+            Some((prefix, digest))
+                if prefix != Self::Xxh3.name()
+                    && digest.len() == 64
+                    && digest
+                        .bytes()
+                        .all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f')) =>
+            {
+                Self::from_name(prefix)?
+            }
+            None if !hash.is_empty() && hash.bytes().all(|b| b.is_ascii_digit()) => Self::Xxh3,
+            _ => bail!("Malformed hash {hash:?}"),
+        };
+
+        Ok(algorithm)
+    }
+}
+
+// Hashes with the given algorithm, prefixing the result with the algorithm's name if needed
 #[cfg(any(feature = "decoding", feature = "encoding"))]
-pub fn hash(input: &[u8]) -> String {
-    xxh3_64(input).to_string()
+pub fn hash(algorithm: HashAlgorithm, input: &[u8]) -> String {
+    let mut hasher = Hasher::new(algorithm);
+    hasher.update(input);
+    hasher.finish()
 }
 
-// Converts the manifest to a string, and then hashes it
-#[cfg(feature = "encoding")]
-pub fn hash_manifest(input: &Vec<(String, String, bool)>) -> String {
-    // Not a true hash. Just a temporary place to dump data, which can then be hashed
-    let mut current_hash = String::new();
+// Streaming variant of `hash`, for data that arrives in pieces
+pub enum Hasher {
+    Xxh3(Box<Xxh3>),
+    Blake3(Box<blake3::Hasher>),
+    Sha256(Sha256),
+}
+
+impl Hasher {
+    pub fn new(algorithm: HashAlgorithm) -> Self {
+        match algorithm {
+            HashAlgorithm::Xxh3 => Self::Xxh3(Box::default()),
+            HashAlgorithm::Blake3 => Self::Blake3(Box::default()),
+            HashAlgorithm::Sha256 => Self::Sha256(Sha256::new()),
+        }
+    }
+
+    pub fn update(&mut self, input: &[u8]) {
+        match self {
+            Self::Xxh3(hasher) => hasher.update(input),
+            Self::Blake3(hasher) => {
+                hasher.update(input);
+            }
+            Self::Sha256(hasher) => hasher.update(input),
+        }
+    }
 
-    for (file, hash, executable) in input {
-        current_hash += file;
-        current_hash += hash;
-        // No clue why rust wants it as a str, but this works.
-        current_hash += executable.to_string().as_str();
+    pub fn finish(self) -> String {
+        match self {
+            Self::Xxh3(hasher) => hasher.digest().to_string(),
+            Self::Blake3(hasher) => format!("blake3-{}", hasher.finalize().to_hex()),
+            Self::Sha256(hasher) => format!("sha256-{:x}", hasher.finalize()),
+        }
     }
+}
 
-    xxh3_64(current_hash.as_bytes()).to_string()
+// Hashes the canonical encoding of the whole manifest, see `Manifest::canonical_bytes`
+#[cfg(any(feature = "decoding", feature = "encoding"))]
+pub fn hash_manifest(algorithm: HashAlgorithm, manifest: &Manifest) -> String {
+    hash(algorithm, &manifest.canonical_bytes())
+}
+
+// Format 1 manifests were hashed over the path, hash and executable bit of every file, concatenated.
+// Ambiguous, as nothing separates them, so only ever used to check manifests that old.
+#[cfg(any(feature = "decoding", feature = "encoding"))]
+pub fn hash_legacy_manifest<'a>(files: impl Iterator<Item = (&'a str, &'a str, bool)>) -> String {
+    let mut hasher = Hasher::new(HashAlgorithm::Xxh3);
+    for (path, hash, executable) in files {
+        hasher.update(path.as_bytes());
+        hasher.update(hash.as_bytes());
+        hasher.update(executable.to_string().as_bytes());
+    }
+    hasher.finish()
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    #[cfg(feature = "encoding")]
+    use crate::manifest::{Attributes, Entry, EntryKind};
+    #[cfg(feature = "encoding")]
+    use std::collections::BTreeMap;
+
+    #[cfg(feature = "encoding")]
+    fn hash_entries(entries: &[Entry], chunks: &BTreeMap<String, Vec<String>>) -> String {
+        let manifest = Manifest {
+            chunks: chunks.clone(),
+            ..Manifest::new(entries.to_vec())
+        };
+
+        hash_manifest(HashAlgorithm::Xxh3, &manifest)
+    }
 
     #[test]
     #[cfg(any(feature = "decoding", feature = "encoding"))]
     fn hash_stable() {
-        let result = hash(&[1, 2, 3]);
+        let result = hash(HashAlgorithm::Xxh3, &[1, 2, 3]);
         assert_eq!(result, "16991689376074199867");
     }
 
     #[test]
     #[cfg(any(feature = "decoding", feature = "encoding"))]
     fn hash_empty_vec() {
-        let result = hash(&[]);
+        let result = hash(HashAlgorithm::Xxh3, &[]);
         assert_eq!(result, "3244421341483603138");
     }
 
+    #[cfg(feature = "encoding")]
+    fn attributes(mode: u32) -> Attributes {
+        Attributes {
+            mode,
+            uid: 0,
+            gid: 0,
+            mtime: 0,
+            xattrs: crate::manifest::Xattrs::new(),
+        }
+    }
+
+    #[cfg(feature = "encoding")]
+    fn file(path: &str, hash: &str, executable: bool) -> Entry {
+        Entry {
+            path: path.to_string(),
+            kind: EntryKind::File {
+                hash: hash.to_string(),
+            },
+            attributes: attributes(if executable { 0o755 } else { 0o644 }),
+        }
+    }
+
     #[test]
     #[cfg(feature = "encoding")]
     fn hash_manifest_stable() {
         let manifest = vec![
-            ("file1.txt".to_string(), "hash1".to_string(), false),
-            ("file2.txt".to_string(), "hash2".to_string(), true),
+            file("file1.txt", "hash1", false),
+            file("file2.txt", "hash2", true),
         ];
-        let result = hash_manifest(&manifest);
-        assert_eq!(result, "1259801786371591190");
+        let result = hash_entries(&manifest, &BTreeMap::new());
+        assert_eq!(result, "12139671347565314272");
     }
 
     #[test]
     #[cfg(feature = "encoding")]
     fn hash_manifest_empty() {
-        let manifest: Vec<(String, String, bool)> = vec![];
-        let result = hash_manifest(&manifest);
-        assert_eq!(result, "3244421341483603138");
+        let manifest: Vec<Entry> = vec![];
+        let result = hash_entries(&manifest, &BTreeMap::new());
+        assert_eq!(result, "8728152977838961610");
     }
 
     #[test]
     #[cfg(feature = "encoding")]
     fn hash_manifest_single_entry() {
-        let manifest = vec![("main.rs".to_string(), "abc123".to_string(), true)];
-        let result = hash_manifest(&manifest);
-        assert_eq!(result, "9815591975043689442");
+        let manifest = vec![file("main.rs", "abc123", true)];
+        let result = hash_entries(&manifest, &BTreeMap::new());
+        assert_eq!(result, "2572819656678095618");
+    }
+
+    #[test]
+    #[cfg(feature = "encoding")]
+    fn hash_manifest_includes_chunks() {
+        let manifest = vec![file("big.img", "abc123", false)];
+        let chunks = BTreeMap::from([(
+            "abc123".to_string(),
+            vec!["def".to_string(), "456".to_string()],
+        )]);
+
+        assert_ne!(
+            hash_entries(&manifest, &chunks),
+            hash_entries(&manifest, &BTreeMap::new())
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "encoding")]
+    fn hash_manifest_symlink_differs_from_file() {
+        let symlink = vec![Entry {
+            path: "/lib".to_string(),
+            kind: EntryKind::Symlink {
+                target: "usr/lib".to_string(),
+            },
+            attributes: attributes(0o777),
+        }];
+        let file = vec![file("/lib", "usr/lib", false)];
+
+        assert_ne!(
+            hash_entries(&symlink, &BTreeMap::new()),
+            hash_entries(&file, &BTreeMap::new())
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "encoding")]
+    fn hash_manifest_includes_attributes() {
+        let manifest = vec![file("/usr/bin/su", "abc123", true)];
+        let mut setuid = manifest.clone();
+        setuid[0].attributes.mode = 0o4755;
+        let mut owned = manifest.clone();
+        owned[0].attributes.uid = 1000;
+        let mut capable = manifest.clone();
+        capable[0]
+            .attributes
+            .xattrs
+            .insert("security.capability".to_string(), vec![1]);
+
+        let hash = |entries: &[Entry]| hash_entries(entries, &BTreeMap::new());
+        assert_ne!(hash(&manifest), hash(&setuid));
+        assert_ne!(hash(&manifest), hash(&owned));
+        assert_ne!(hash(&manifest), hash(&capable));
+    }
+
+    #[test]
+    #[cfg(feature = "encoding")]
+    fn hash_manifest_is_unambiguous() {
+        assert_ne!(
+            hash_entries(&[file("a1", "23", false)], &BTreeMap::new()),
+            hash_entries(&[file("a", "123", false)], &BTreeMap::new())
+        );
+        assert_ne!(
+            hash_entries(
+                &[file("a", "1", false), file("b", "2", false)],
+                &BTreeMap::new()
+            ),
+            hash_entries(&[file("a", "1b2", false)], &BTreeMap::new())
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "encoding")]
+    fn hash_manifest_includes_format() {
+        let mut manifest = Manifest::new(vec![file("a", "1", false)]);
+        let current = hash_manifest(HashAlgorithm::Xxh3, &manifest);
+        manifest.format -= 1;
+
+        assert_ne!(hash_manifest(HashAlgorithm::Xxh3, &manifest), current);
+    }
+
+    #[test]
+    #[cfg(feature = "decoding")]
+    fn hash_legacy_manifest_stable() {
+        let files = [("file1.txt", "hash1", false), ("file2.txt", "hash2", true)];
+        assert_eq!(
+            hash_legacy_manifest(files.into_iter()),
+            "1259801786371591190"
+        );
+        assert_eq!(
+            hash_legacy_manifest([("main.rs", "abc123", true)].into_iter()),
+            "9815591975043689442"
+        );
+        assert_eq!(
+            hash_legacy_manifest(std::iter::empty()),
+            "3244421341483603138"
+        );
+    }
+
+    #[test]
+    #[cfg(any(feature = "decoding", feature = "encoding"))]
+    fn hash_blake3_empty() {
+        assert_eq!(
+            hash(HashAlgorithm::Blake3, &[]),
+            "blake3-af1349b9f5f9a1a6a0404dea36dcc9499bcb25c9adc112b7cc9a93cae41f3262"
+        );
+    }
+
+    #[test]
+    #[cfg(any(feature = "decoding", feature = "encoding"))]
+    fn hash_sha256_empty() {
+        assert_eq!(
+            hash(HashAlgorithm::Sha256, &[]),
+            "sha256-e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+    }
+
+    #[test]
+    #[cfg(any(feature = "decoding", feature = "encoding"))]
+    fn algorithm_of_hash() {
+        assert_eq!(
+            HashAlgorithm::of("16991689376074199867").unwrap(),
+            HashAlgorithm::Xxh3
+        );
+        assert_eq!(
+            HashAlgorithm::of(&hash(HashAlgorithm::Blake3, &[])).unwrap(),
+            HashAlgorithm::Blake3
+        );
+        assert_eq!(
+            HashAlgorithm::of(&hash(HashAlgorithm::Sha256, &[])).unwrap(),
+            HashAlgorithm::Sha256
+        );
+        assert!(HashAlgorithm::of("blake3-af13").is_err());
+        assert!(HashAlgorithm::of(&format!("md5-{}", "0".repeat(64))).is_err());
+        assert!(HashAlgorithm::of("xxh3-123").is_err());
+        assert!(HashAlgorithm::of("../etc/passwd").is_err());
+        assert!(HashAlgorithm::of("").is_err());
+    }
+
+    #[test]
+    #[cfg(any(feature = "decoding", feature = "encoding"))]
+    fn hasher_matches_hash() {
+        for algorithm in [
+            HashAlgorithm::Xxh3,
+            HashAlgorithm::Blake3,
+            HashAlgorithm::Sha256,
+        ] {
+            let mut hasher = Hasher::new(algorithm);
+            hasher.update(&[1, 2]);
+            hasher.update(&[3]);
+            assert_eq!(hasher.finish(), hash(algorithm, &[1, 2, 3]));
+        }
     }
 }
@@ -3,6 +3,10 @@
 use anyhow::Context;
 use anyhow::Result;
 use anyhow::bail;
+#[cfg(feature = "encoding")]
+use std::collections::BTreeSet;
+#[cfg(feature = "decoding")]
+use std::collections::HashMap;
 use std::fs::create_dir_all;
 use std::{
     fs,
@@ -10,15 +14,33 @@ use std::{
 };
 
 mod artifacts;
+#[cfg(feature = "encoding")]
+mod atomic;
+#[cfg(any(feature = "encoding", feature = "decoding"))]
+mod binary;
+#[cfg(feature = "encoding")]
+mod chunking;
 mod compression;
+mod error;
 mod hash;
+mod manifest;
 mod network;
+#[cfg(feature = "decoding")]
+mod paths;
+#[cfg(any(feature = "encoding", feature = "decoding"))]
+mod tree;
+#[cfg(any(feature = "encoding", feature = "decoding"))]
+mod xattrs;
 
-#[derive(serde::Serialize, serde::Deserialize)]
-struct Manifest {
-    files: Vec<(String, String, bool)>, // (path, hash, executable)
-    format: u8,
-}
+pub use artifacts::ArtifactVersion;
+#[cfg(feature = "decoding")]
+pub use artifacts::VersionSelector;
+pub use error::{IndexError, InstallError};
+pub use hash::HashAlgorithm;
+#[cfg(feature = "decoding")]
+use manifest::Entry;
+pub use manifest::Metadata;
+use manifest::{EntryKind, Manifest};
 
 pub enum RepoType {
     Local,
@@ -38,7 +60,197 @@ pub struct Store {
     pub path: PathBuf,
 }
 
-/// Attempts to create the repo and it's associated directories.
+/// An artifact available from one of a Store's repos, from [`Store::list_artifacts`].
+#[cfg(feature = "decoding")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AvailableArtifact {
+    pub name: String,
+    /// The repo it's available from, as listed in [`Store::repos`].
+    pub repo: String,
+    /// The manifest hash of its latest build.
+    pub manifest_hash: String,
+    /// When its latest build was added to the repo, see [`ArtifactVersion::timestamp`].
+    pub timestamp: Option<i64>,
+    /// The version its latest build was built as, if one was given.
+    pub version: Option<String>,
+    /// The manifest hash each of its channels points to, see [`list_channels`].
+    pub channels: std::collections::BTreeMap<String, String>,
+    /// Where its latest build came from, as recorded in its manifest.
+    pub metadata: Metadata,
+}
+
+#[cfg(feature = "decoding")]
+impl Store {
+    /// Lists every artifact available from the Store's repos, sorted by name.
+    ///
+    /// Each is listed with its latest build, and the metadata recorded in that build's manifest.
+    /// An artifact in several repos is listed once for each different build, in the order of the repos, so the first
+    /// of each name is the one [`install_artifact`] installs. The same build in several repos is only listed once,
+    /// from the first of them.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if any repo's artifacts index can't be fetched or is invalid, or if any listed manifest can't
+    /// be fetched or doesn't match its hash.
+    pub fn list_artifacts(&self) -> Result<Vec<AvailableArtifact>> {
+        use std::collections::HashSet;
+
+        let mut available = Vec::new();
+        let mut seen = HashSet::new();
+
+        for repo in &self.repos {
+            for (name, artifact) in artifacts::get_artifacts(&fetch_index(self, repo)?)? {
+                let Some(latest) = artifact.versions.last() else {
+                    continue;
+                };
+                if seen.insert((name.clone(), latest.manifest_hash.clone())) {
+                    available.push(available_artifact(name, repo, artifact, self)?);
+                }
+            }
+        }
+
+        // Stable, so every build of the same name stays in the order of its repo
+        available.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(available)
+    }
+
+    /// Finds the artifact [`install_artifact`] would install, from the first of the Store's repos that has it.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a repo's artifacts index can't be fetched or is invalid, or if the artifact's manifest can't
+    /// be fetched or doesn't match its hash.
+    pub fn find_artifact(&self, artifact_name: &str) -> Result<Option<AvailableArtifact>> {
+        let Some((repo, index, _)) = find_index(self, artifact_name, &VersionSelector::Latest)?
+        else {
+            return Ok(None);
+        };
+        let artifact = artifacts::get_artifacts(&index)?
+            .remove(artifact_name)
+            .unwrap_or_default();
+
+        available_artifact(artifact_name.to_string(), repo, artifact, self).map(Some)
+    }
+}
+
+// Describes an artifact in one of the Store's repos, reading the metadata of its latest build
+#[cfg(feature = "decoding")]
+fn available_artifact(
+    name: String,
+    repo: &str,
+    artifact: artifacts::Artifact,
+    store: &Store,
+) -> Result<AvailableArtifact> {
+    let latest = artifact
+        .versions
+        .into_iter()
+        .last()
+        .ok_or_else(|| anyhow::anyhow!("{name:?} has no builds"))?;
+    let manifest = read_manifest(&latest.manifest_hash, store)?;
+
+    Ok(AvailableArtifact {
+        name,
+        repo: repo.to_string(),
+        manifest_hash: latest.manifest_hash,
+        timestamp: latest.timestamp,
+        version: latest.version,
+        channels: artifact.channels,
+        metadata: manifest.metadata,
+    })
+}
+
+/// Options for [`build_with_options`]. The defaults match [`build`].
+#[cfg(feature = "encoding")]
+pub struct BuildOptions {
+    /// Namespaces of extended attributes to capture from files and directories, such as `security` or `user`.
+    /// An entry may also name a single attribute, like `security.capability`. Nothing is captured by default.
+    pub xattr_namespaces: Vec<String>,
+    /// How many files are read, hashed, compressed and written at once. Defaults to the number of available CPUs.
+    /// The manifest is the same no matter how many workers are used.
+    pub workers: usize,
+    /// Stores every directory as its own tree, rather than listing every path in the manifest.
+    /// Artifacts sharing a directory then share its tree, and installs skip trees that are already in the Store.
+    pub trees: bool,
+    /// Where the artifact came from, such as the commit and CI job that built it. Empty by default.
+    /// It's part of the manifest, so artifacts with different metadata have different hashes.
+    pub metadata: Metadata,
+    /// The version the artifact is built as, listed by [`list_versions`] and installable with
+    /// [`install_artifact_version`]. Unlike `metadata`, it's only kept in the repo's index, not in the manifest.
+    pub version: Option<String>,
+    /// Channels (or tags) to point at the new build, such as `nightly`. Others can be pointed at it later with
+    /// [`promote`], and any of them can be installed with [`VersionSelector::Channel`].
+    pub channels: Vec<String>,
+    /// How modification times are recorded. Every entry gets the epoch by default.
+    pub mtimes: Mtimes,
+}
+
+/// How [`build_with_options`] records modification times.
+///
+/// Installed objects are shared by content and attributes, including the mtime, so times that change between
+/// rebuilds of the same files stop the Store from sharing them, and the manifest from being reproducible.
+#[cfg(feature = "encoding")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mtimes {
+    /// Every entry gets this time, in seconds since the epoch.
+    Fixed(i64),
+    /// Times later than this are lowered to it, like `SOURCE_DATE_EPOCH` does for other build tools.
+    Clamp(i64),
+    /// Every entry keeps its own time, so rebuilding touched files gives a different manifest.
+    Preserve,
+}
+
+#[cfg(feature = "encoding")]
+impl Mtimes {
+    fn apply(self, mtime: i64) -> i64 {
+        match self {
+            Self::Fixed(fixed) => fixed,
+            Self::Clamp(latest) => mtime.min(latest),
+            Self::Preserve => mtime,
+        }
+    }
+}
+
+/// What [`build_with_options`] wrote to the repo.
+#[cfg(feature = "encoding")]
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct BuildReport {
+    pub manifest_hash: String,
+    /// Chunks that weren't in the repo yet, so were compressed and written.
+    pub new_chunks: u64,
+    /// Chunks that were already in the repo, so were skipped.
+    pub reused_chunks: u64,
+    /// Size of the new chunks, before compression.
+    pub new_bytes: u64,
+    /// Size of the reused chunks, before compression.
+    pub reused_bytes: u64,
+}
+
+#[cfg(feature = "encoding")]
+impl BuildReport {
+    fn add(&mut self, other: &Self) {
+        self.new_chunks += other.new_chunks;
+        self.reused_chunks += other.reused_chunks;
+        self.new_bytes += other.new_bytes;
+        self.reused_bytes += other.reused_bytes;
+    }
+}
+
+#[cfg(feature = "encoding")]
+impl Default for BuildOptions {
+    fn default() -> Self {
+        Self {
+            xattr_namespaces: Vec::new(),
+            workers: std::thread::available_parallelism().map_or(1, std::num::NonZero::get),
+            trees: false,
+            metadata: Metadata::default(),
+            version: None,
+            channels: Vec::new(),
+            mtimes: Mtimes::Fixed(0),
+        }
+    }
+}
+
+/// Attempts to create the repo and it's associated directories, using `xxh3` to hash its contents.
 ///
 /// This operation will create all parent directories if they do not exist.
 /// Any errors encountered during directory creation are ignored.
@@ -56,16 +268,47 @@ pub struct Store {
 /// Any errors returned by `fs::create_dir_all` are ignored.
 #[cfg(feature = "encoding")]
 pub fn create_repo(repo_dir: &Path) -> Result<()> {
+    create_repo_with_algorithm(repo_dir, HashAlgorithm::default())
+}
+
+/// Attempts to create the repo and it's associated directories, hashing its contents with `algorithm`.
+///
+/// Every chunk and manifest later built into this repo is named with this algorithm.
+/// Use a cryptographic algorithm (`Blake3` or `Sha256`) if anyone who can write to the repo is untrusted.
+///
+/// # Arguments
+///
+/// * `repo_dir` - The base directory for the repo.
+/// * `algorithm` - The hash algorithm used by every build into this repo.
+///
+/// # Errors
+///
+/// Returns an error if the repo already exists, or it's directories couldn't be created.
+#[cfg(feature = "encoding")]
+pub fn create_repo_with_algorithm(repo_dir: &Path, algorithm: HashAlgorithm) -> Result<()> {
     if repo_dir.exists() {
         bail!("Already exists! {}", repo_dir.display())
     }
 
     () = fs::create_dir_all(repo_dir.join("chunks"))?;
     () = fs::create_dir_all(repo_dir.join("manifests"))?;
+    () = fs::write(repo_dir.join("hash_algorithm"), algorithm.name())?;
 
     Ok(())
 }
 
+// Repos created before `hash_algorithm` existed are always xxh3
+#[cfg(feature = "encoding")]
+fn read_repo_algorithm(repo_dir: &Path) -> Result<HashAlgorithm> {
+    let algorithm_path = repo_dir.join("hash_algorithm");
+
+    if !algorithm_path.exists() {
+        return Ok(HashAlgorithm::Xxh3);
+    }
+
+    HashAlgorithm::from_name(fs::read_to_string(algorithm_path)?.trim())
+}
+
 /// Attempts to create the Store and it's associated directories.
 ///
 /// This operation will create all parent directories if they do not exist.
@@ -90,7 +333,7 @@ pub fn create_store(store: &Store) -> Result<()> {
         bail!("Store already exists! Make sure the directory doesn't exist, or you're operating on the correct directory.".to_string());
     }
 
-    fs::create_dir_all(store.path.join("chunks"))?;
+    fs::create_dir_all(store.path.join("objects"))?;
     fs::create_dir_all(store.path.join("manifests"))?;
     fs::create_dir_all(store.path.join("artifacts"))?;
 
@@ -100,6 +343,14 @@ pub fn create_store(store: &Store) -> Result<()> {
 /// Creates a manifest and its associated chunks from a directory structure, and saves it into the list of artifacts.
 ///
 /// This function walks the given input directory, compresses and hashes each file, and stores the resulting chunks and manifest in the repository.
+/// Large files are split with content-defined chunking, so a small edit only produces a few new chunks.
+/// Symlinks are recorded with their target, and are never followed. Directories are recorded too, so empty ones survive.
+/// Files with several hard links in `input_dir` are stored once, and every other path to them is recorded as a hard link.
+/// Device nodes, FIFOs and sockets are recorded as-is, without reading them.
+/// Every entry keeps its mode and owner. Modification times are all recorded as the epoch, so rebuilds match.
+/// The size of every file and the compressed size of every chunk are recorded, so installs can be planned beforehand.
+/// Entries are listed depth-first, every directory before its contents and each directory's contents sorted by name,
+/// so the same tree always produces the same manifest hash, on any filesystem.
 /// The manifest is then registered as an artifact under the specified name.
 ///
 /// # Arguments
@@ -117,424 +368,3034 @@ pub fn create_store(store: &Store) -> Result<()> {
 /// Returns an error if:
 /// - Any file in the input directory cannot be read.
 /// - Any chunk or manifest cannot be written to the repository.
+/// - The artifact name isn't a valid name, or the repository's artifacts index is invalid, see [`IndexError`].
 /// - Any other I/O or processing error occurs during the build process.
 #[cfg(feature = "encoding")]
 pub fn build(input_dir: &PathBuf, repo_dir: &Path, artifact_name: &str) -> Result<String> {
-    use std::os::unix::fs::PermissionsExt;
+    build_with_options(input_dir, repo_dir, artifact_name, &BuildOptions::default())
+        .map(|report| report.manifest_hash)
+}
+
+/// Creates a manifest and its associated chunks from a directory structure, like [`build`], with extra options.
+///
+/// Extended attributes in `options.xattr_namespaces` are recorded for every file and directory, and
+/// `options.metadata` is recorded in the manifest. Modification times are recorded as `options.mtimes` says.
+/// With `options.trees`, every directory is written to the repo's `trees` as its own tree, and the manifest only
+/// names the root one.
+/// Chunks already in the repo are never compressed or written again, and the returned report says how many were reused.
+///
+/// # Arguments
+///
+/// * `input_dir` - The directory containing files to be added to the artifact.
+/// * `repo_dir` - The base directory of the repository where chunks and manifests will be stored.
+/// * `artifact_name` - The name under which the artifact will be registered.
+/// * `options` - What to capture from `input_dir`, see [`BuildOptions`].
+///
+/// # Errors
+///
+/// Returns an error for the same reasons as [`build`], or if any extended attributes can't be read.
+#[cfg(feature = "encoding")]
+pub fn build_with_options(
+    input_dir: &PathBuf,
+    repo_dir: &Path,
+    artifact_name: &str,
+    options: &BuildOptions,
+) -> Result<BuildReport> {
+    use crate::manifest::{Attributes, Entry};
+    use std::collections::{BTreeMap, HashMap};
+    use std::os::unix::fs::MetadataExt;
     use walkdir::WalkDir;
 
-    // List of all files used by the new manifest
+    // List of all entries used by the new manifest
+    let mut entries = Vec::new();
+    // Every regular file to be read, alongside the index of its entry
     let mut files = Vec::new();
+    // First path seen of every file with several hard links, by (device, inode)
+    let mut hardlinks = HashMap::new();
+    // Chunk lists of every file that was split
+    let mut chunks = BTreeMap::new();
+    let algorithm = read_repo_algorithm(repo_dir)?;
     // Define some directories
     let chunk_dir = repo_dir.join("chunks");
-    let manifest_dir = repo_dir.join("manifests");
-    let artifacts_file_path = repo_dir.join("artifacts");
+
+    // Checked up front, rather than once everything has been written
+    artifacts::check_name(artifact_name)?;
+    for channel in &options.channels {
+        artifacts::check_name(channel)?;
+    }
 
     // Walk the input directory and process files
+    // Sorted, so the manifest doesn't depend on the order the filesystem lists directories in
     for entry in WalkDir::new(input_dir)
+        .sort_by_file_name()
         .into_iter()
         .filter_map(Result::ok)
-        .filter(|e| e.file_type().is_file())
     {
-        let root_path = input_dir.to_string_lossy().to_string();
-        let path = entry.path().to_string_lossy().to_string();
-        let raw = fs::read(&path)
-            .map_err(|x| anyhow::anyhow!("Couldn't read {:?} with error {}", &path, x))?;
-        let compressed = compression::compress_file(&raw, 3);
-        let hash = hash::hash(&raw);
+        // The root itself is the artifact, so it isn't recorded
+        if entry.depth() == 0 {
+            continue;
+        }
+
+        let Some(relative_path) = entry.path().strip_prefix(input_dir)?.to_str() else {
+            bail!("{} isn't valid UTF-8", entry.path().display());
+        };
+        let manifest_path = format!("/{relative_path}");
+
+        // Doesn't follow symlinks, so they get their own attributes
+        let metadata = entry.metadata()?;
+        let attributes = Attributes {
+            mode: metadata.mode() & 0o7777,
+            uid: metadata.uid(),
+            gid: metadata.gid(),
+            mtime: options.mtimes.apply(metadata.mtime()),
+            // Only files and directories keep xattrs, as symlinks and special files can only hold a few kinds
+            xattrs: if metadata.is_file() || metadata.is_dir() {
+                xattrs::read(entry.path(), &options.xattr_namespaces)?
+            } else {
+                manifest::Xattrs::new()
+            },
+        };
+
+        let inode = (metadata.dev(), metadata.ino());
+        let linked = metadata.is_file() && metadata.nlink() > 1;
+
+        let kind = if entry.file_type().is_symlink() {
+            let target = fs::read_link(entry.path())?;
+            let Some(target) = target.to_str() else {
+                bail!("The target of {} isn't valid UTF-8", entry.path().display());
+            };
+            EntryKind::Symlink {
+                target: target.to_string(),
+            }
+        } else if let Some(target) = hardlinks.get(&inode).filter(|_| linked) {
+            // Only the first path to a hard linked file is stored, the rest point to it
+            EntryKind::Hardlink {
+                target: String::clone(target),
+            }
+        } else if entry.file_type().is_dir() {
+            EntryKind::Directory
+        } else if entry.file_type().is_file() {
+            if linked {
+                hardlinks.insert(inode, manifest_path.clone());
+            }
+
+            // Hashed later, alongside every other file
+            files.push((entries.len(), entry.into_path()));
+            EntryKind::File {
+                hash: String::new(),
+            }
+        } else if let Some(kind) = special_kind(&metadata) {
+            kind
+        } else {
+            continue;
+        };
 
-        // Determine if the file is executable
-        let is_executable = entry.path().metadata()?.permissions().mode() & 0o111 != 0;
+        entries.push(Entry {
+            path: manifest_path,
+            kind,
+            attributes,
+        });
+    }
 
-        // Save the chunk
-        fs::write(chunk_dir.join(&hash), compressed)?;
+    let (indices, paths): (Vec<_>, Vec<_>) = files.into_iter().unzip();
+    let (written, mut report) = write_files(&paths, options.workers, algorithm, &chunk_dir)?;
 
-        files.push((path.replacen(&root_path, "", 1), hash, is_executable));
+    let mut sizes = BTreeMap::new();
+    let mut chunk_sizes = BTreeMap::new();
+    for (index, file) in indices.into_iter().zip(written) {
+        // Files stored as a single chunk are named by their own hash, so don't need a chunk list
+        if file.chunks.len() > 1 {
+            let chunk_hashes = file.chunks.iter().map(|(hash, _)| hash.clone()).collect();
+            chunks.insert(file.hash.clone(), chunk_hashes);
+        }
+        sizes.insert(file.hash.clone(), file.size);
+        chunk_sizes.extend(file.chunks);
+        entries[index].kind = EntryKind::File { hash: file.hash };
     }
 
-    let manifest = Manifest { format: 1, files };
+    let manifest = Manifest {
+        format: manifest::FORMAT,
+        entries,
+        chunks,
+        sizes,
+        chunk_sizes,
+        root: None,
+        metadata: options.metadata.clone(),
+    };
+
+    report.manifest_hash = write_manifest(manifest, algorithm, repo_dir, artifact_name, options)?;
+    Ok(report)
+}
+
+// Writes a built manifest (and its trees, if wanted) to the repo, and adds it to the artifacts index
+#[cfg(feature = "encoding")]
+fn write_manifest(
+    mut manifest: Manifest,
+    algorithm: HashAlgorithm,
+    repo_dir: &Path,
+    artifact_name: &str,
+    options: &BuildOptions,
+) -> Result<String> {
+    if options.trees {
+        manifest = tree::write_trees(manifest, algorithm, &repo_dir.join("trees"))?;
+    }
 
-    let manifest_hash = hash::hash_manifest(&manifest.files);
+    let manifest_hash = hash::hash_manifest(algorithm, &manifest);
 
-    // Write the manifest to the repo directory
-    fs::write(
-        manifest_dir.join(&manifest_hash),
-        serde_json::to_string_pretty(&manifest)?,
+    // Write the manifest to the repo directory.
+    // It's written in the same encoding it's hashed over, so the same manifest is always the same bytes.
+    atomic::write_new(
+        &repo_dir.join("manifests").join(&manifest_hash),
+        &manifest.encode(),
     )?;
 
+    // Earlier builds stay in the index, so they can still be installed
+    let timestamp = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)?
+        .as_secs();
     artifacts::add_artifact(
-        artifact_name.to_string(),
-        manifest_hash.to_string(),
-        &artifacts_file_path,
-    );
+        artifact_name,
+        ArtifactVersion {
+            manifest_hash: manifest_hash.clone(),
+            timestamp: Some(i64::try_from(timestamp)?),
+            version: options.version.clone(),
+        },
+        &options.channels,
+        &repo_dir.join("artifacts"),
+    )?;
 
     Ok(manifest_hash)
 }
 
-/// Installs an Artifact by name.
+/// Points `to_channel` of an artifact at whichever build `from_channel` points to, without rebuilding it.
+/// Returns the hash of the promoted manifest.
 ///
 /// # Arguments
 ///
-/// * `artifact_name` - The name of the artifact to install.
-/// * `store` - The correlated Store struct.
+/// * `repo_dir` - The base directory for the repo.
+/// * `artifact_name` - The name of the artifact.
+/// * `from_channel` - The channel to promote from, such as `beta`.
+/// * `to_channel` - The channel to point at the same build, such as `stable`. It's created if it doesn't exist.
 ///
 /// # Errors
-/// Returns an error if the artifact does not exist, or if any file operations fail.
-#[cfg(feature = "decoding")]
-pub fn install_artifact(artifact_name: &String, store: &Store) -> Result<()> {
-    use crate::artifacts::get_artifact;
-    use anyhow::anyhow;
-    use std::fs::{create_dir_all, rename};
-    use std::os::unix::fs::symlink;
-
-    let store_chunk_dir = store.path.join("chunks");
-    let store_manifest_dir = store.path.join("manifests");
-    let store_artifacts_path = store.path.join("artifacts");
-
-    let manifest_hash = get_artifact(
+///
+/// Returns [`IndexError::UnknownChannel`] if `from_channel` doesn't exist, [`IndexError::InvalidName`] if `to_channel`
+/// isn't a valid channel name, or another [`IndexError`] if the repo's artifacts index is invalid.
+#[cfg(feature = "encoding")]
+pub fn promote(
+    repo_dir: &Path,
+    artifact_name: &str,
+    from_channel: &str,
+    to_channel: &str,
+) -> Result<String> {
+    artifacts::promote(
         artifact_name,
-        &resolve_repo_path(store, &"artifacts".to_string())?,
+        from_channel,
+        to_channel,
+        &repo_dir.join("artifacts"),
     )
-    .ok_or_else(|| anyhow!("Tried to get a manifest that didn't exist"))?;
-
-    let manifest: Manifest = serde_json::from_str(
-        fs::read_to_string(resolve_repo_path(
-            store,
-            &format!("manifests/{manifest_hash}"),
-        )?)?
-        .as_str(),
-    )?;
-
-    for (_path, hash, executable) in &manifest.files {
-        // Install chunks
-        install_chunk(hash, store)?;
-
-        // Make sure it's executable if it needs to be
-        if *executable {
-            // TODO: test this
-            make_chunk_executable(hash, &store.path)?;
-        }
-    }
-
-    // Seperate to ensure chunks have been installed prior to linked
-    for (manifest_defined_path, hash, _executable) in &manifest.files {
-        let manifest_defined_path = manifest_defined_path.trim_start_matches('/').to_string();
-
-        let path = store_manifest_dir
-            .join(&manifest_hash)
-            .join(manifest_defined_path);
-
-        if !path
-            .parent()
-            .ok_or_else(|| anyhow::anyhow!("Failed to get parent directory"))?
-            .exists()
-        {
-            create_dir_all(
-                path.parent()
-                    .ok_or_else(|| anyhow::anyhow!("Failed to get parent directory"))?,
-            )?;
-        }
-
-        if !&path.try_exists()? {
-            symlink(store_chunk_dir.as_path().join(hash), path)?;
-        }
-    }
-
-    // Create a temporary symlink for atomic update
-    let tmp_file_name = get_temp_file(None, &store_artifacts_path);
-
-    let tmp_symlink = store_artifacts_path.join(&tmp_file_name);
-    let final_symlink = store_artifacts_path.join(artifact_name);
-
-    symlink(store_manifest_dir.join(&manifest_hash), &tmp_symlink)?;
-    rename(&tmp_symlink, &final_symlink)?;
+}
 
+/// Removes an artifact from a repo, with every one of its builds and channels.
+///
+/// Its manifests and chunks are left in the repo, see [`remove_artifact_with_report`] to find which are no longer
+/// needed.
+///
+/// # Arguments
+///
+/// * `repo_dir` - The base directory for the repo.
+/// * `artifact_name` - The name of the artifact to remove.
+///
+/// # Errors
+///
+/// Returns [`IndexError::UnknownArtifact`] if there's no such artifact, or another [`IndexError`] if the repo's
+/// artifacts index is invalid.
+#[cfg(feature = "encoding")]
+pub fn remove_artifact(repo_dir: &Path, artifact_name: &str) -> Result<()> {
+    artifacts::remove_artifact(artifact_name, &repo_dir.join("artifacts"))?;
     Ok(())
 }
 
-#[cfg(feature = "decoding")]
-fn resolve_repo_path(store: &Store, path: &String) -> Result<PathBuf> {
-    if store.cache_path.join(path).exists() {
-        return Ok(store.cache_path.join(path));
-    }
+/// What a repo no longer references after [`remove_artifact_with_report`].
+#[cfg(feature = "encoding")]
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Unreferenced {
+    /// Manifests that were only builds of the removed artifact.
+    pub manifests: BTreeSet<String>,
+    /// Trees that were only in those manifests.
+    pub trees: BTreeSet<String>,
+    /// Chunks that were only in those manifests.
+    pub chunks: BTreeSet<String>,
+}
 
-    let joined_path = store.cache_path.join(path);
-    let parent = joined_path
-        .parent()
-        .ok_or_else(|| anyhow::anyhow!("Failed to get parent directory"))?;
-    create_dir_all(parent)?;
+/// Removes an artifact from a repo like [`remove_artifact`], and reports which of its manifests, trees and chunks no
+/// other artifact in the repo uses, so they can be deleted.
+///
+/// Every remaining manifest is read to find this, and the report is only accurate if nothing else is building into
+/// the repo meanwhile.
+///
+/// # Arguments
+///
+/// * `repo_dir` - The base directory for the repo.
+/// * `artifact_name` - The name of the artifact to remove.
+///
+/// # Errors
+///
+/// Returns an error for the same reasons as [`remove_artifact`], or if any manifest or tree in the repo is missing
+/// or invalid. The artifact is removed even if the report fails.
+#[cfg(feature = "encoding")]
+pub fn remove_artifact_with_report(repo_dir: &Path, artifact_name: &str) -> Result<Unreferenced> {
+    let artifacts_file_path = repo_dir.join("artifacts");
+    let removed = artifacts::remove_artifact(artifact_name, &artifacts_file_path)?;
+    let remaining = artifacts::all_manifests(&artifacts_file_path)?;
 
-    // List of all errors accumulated in the next for loop.
-    let mut error_list = vec![];
+    let manifests: BTreeSet<String> = removed
+        .into_iter()
+        .map(|version| version.manifest_hash)
+        .filter(|manifest_hash| !remaining.contains(manifest_hash))
+        .collect();
+
+    // Only what the unreferenced manifests used can have become unreferenced
+    let (trees, chunks) = repo_references(repo_dir, &manifests)?;
+    let (kept_trees, kept_chunks) = repo_references(repo_dir, &remaining)?;
+
+    Ok(Unreferenced {
+        manifests,
+        trees: trees.difference(&kept_trees).cloned().collect(),
+        chunks: chunks.difference(&kept_chunks).cloned().collect(),
+    })
+}
 
-    for repo in &store.repos {
-        let result = match store.kind {
-            RepoType::Https => {
-                network::download_file(repo, &store.cache_path.join(path)).map(|_| ())
-            }
-            RepoType::Local => {
-                fs::copy(PathBuf::from(&repo).join(path), store.cache_path.join(path))
-                    .map(|_| ())
-                    .map_err(|e| anyhow::anyhow!(e))
-            }
+// Every tree and chunk used by some of a repo's manifests
+#[cfg(feature = "encoding")]
+fn repo_references(
+    repo_dir: &Path,
+    manifest_hashes: &BTreeSet<String>,
+) -> Result<(BTreeSet<String>, BTreeSet<String>)> {
+    let mut trees = BTreeSet::new();
+    let mut chunks = BTreeSet::new();
+
+    for manifest_hash in manifest_hashes {
+        let manifest = Manifest::decode_verified(
+            &fs::read(repo_dir.join("manifests").join(manifest_hash))?,
+            manifest_hash,
+        )?;
+        let flattened = match manifest.root.clone() {
+            Some(root) => tree::flatten(&root, &mut |hash| {
+                Ok(fs::read(repo_dir.join("trees").join(hash))?)
+            })?,
+            None => tree::Flattened {
+                manifest,
+                trees: Vec::new(),
+            },
         };
 
-        if result.is_ok() {
-            return Ok(store.cache_path.join(path));
+        trees.extend(flattened.trees.into_iter().map(|tree| tree.hash));
+        for entry in &flattened.manifest.entries {
+            if let EntryKind::File { hash } = &entry.kind {
+                let file_chunks = flattened.manifest.chunks.get(hash);
+                chunks.extend(
+                    file_chunks
+                        .map_or(std::slice::from_ref(hash), Vec::as_slice)
+                        .iter()
+                        .cloned(),
+                );
+            }
         }
-
-        // If error, just add to `error_list`` and continue to next repo, do not return error
-        error_list.push(result.unwrap_err());
     }
 
-    Err(anyhow::anyhow!("{:?}", error_list))
+    Ok((trees, chunks))
 }
 
-#[cfg(feature = "decoding")]
-fn get_temp_file(potential: Option<u8>, dir: &Path) -> PathBuf {
-    let potential = potential.unwrap_or_default();
-
-    let file_name = format!(".tmp_{potential}");
-
-    // Overflow protection, if the potential number is too high, we clear the oldest old temp file and replace it.
-    if potential > u8::MAX - 1 {
-        // Find the most recently modified temp file and remove it to prevent overflow
-        if let Ok(entries) = std::fs::read_dir(dir) {
-            let oldest_temp = entries
-                .flatten()
-                .filter(|f| {
-                    f.file_type().map(|ft| ft.is_file()).unwrap_or(false)
-                        && f.file_name().to_string_lossy().starts_with(".tmp_")
-                })
-                .min_by_key(|f| f.metadata().and_then(|m| m.modified()).ok())
-                .map(|f| f.path())
-                .unwrap();
+/// Renames an artifact in a repo, keeping every one of its builds and channels.
+///
+/// Stores that installed it under its old name keep it there, until it's installed under the new one.
+///
+/// # Arguments
+///
+/// * `repo_dir` - The base directory for the repo.
+/// * `from_name` - The artifact's current name.
+/// * `to_name` - Its new name, which no other artifact can have.
+///
+/// # Errors
+///
+/// Returns [`IndexError::UnknownArtifact`] if there's no artifact named `from_name`, [`IndexError::ArtifactExists`]
+/// if there already is one named `to_name`, [`IndexError::InvalidName`] if `to_name` isn't a valid name, or another
+/// [`IndexError`] if the repo's artifacts index is invalid.
+#[cfg(feature = "encoding")]
+pub fn rename_artifact(repo_dir: &Path, from_name: &str, to_name: &str) -> Result<()> {
+    artifacts::rename_artifact(from_name, to_name, &repo_dir.join("artifacts"))
+}
 
-            fs::remove_file(&oldest_temp).unwrap_or_else(|x| {
-                panic!("Failed to remove old temp symlink: {x}");
-            });
+// Device nodes, FIFOs and sockets are recorded without reading them
+#[cfg(feature = "encoding")]
+fn special_kind(metadata: &fs::Metadata) -> Option<EntryKind> {
+    use std::os::unix::fs::{FileTypeExt, MetadataExt};
 
-            return oldest_temp;
-        }
-    }
+    let file_type = metadata.file_type();
+    let (major, minor) = (
+        rustix::fs::major(metadata.rdev()),
+        rustix::fs::minor(metadata.rdev()),
+    );
 
-    // Check if the file already exists, if it does, increment the potential number
-    if dir.join(&file_name).exists() {
-        get_temp_file(Some(potential + 1), dir)
+    if file_type.is_char_device() {
+        Some(EntryKind::CharDevice { major, minor })
+    } else if file_type.is_block_device() {
+        Some(EntryKind::BlockDevice { major, minor })
+    } else if file_type.is_fifo() {
+        Some(EntryKind::Fifo)
+    } else if file_type.is_socket() {
+        Some(EntryKind::Socket)
     } else {
-        dir.join(&file_name)
+        None
     }
 }
 
-#[cfg(feature = "decoding")]
-fn make_chunk_executable(chunk_hash: &String, store_path: &Path) -> Result<()> {
-    use std::os::unix::fs::PermissionsExt;
+// A file's hash and size, and the hash and compressed size of each of its chunks.
+// Files that weren't split have a single chunk, named by the file's own hash.
+#[cfg(feature = "encoding")]
+struct WrittenFile {
+    hash: String,
+    size: u64,
+    chunks: Vec<(String, u64)>,
+}
 
-    let chunk_path = store_path.join("chunks").join(chunk_hash);
+// Reads, hashes, compresses and writes every file, on up to `workers` threads at once.
+// Results are in the same order as `paths`, so the manifest never depends on which file finished first.
+#[cfg(feature = "encoding")]
+fn write_files(
+    paths: &[PathBuf],
+    workers: usize,
+    algorithm: HashAlgorithm,
+    chunk_dir: &Path,
+) -> Result<(Vec<WrittenFile>, BuildReport)> {
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+    let next = AtomicUsize::new(0);
+    let failed = AtomicBool::new(false);
+
+    let worker = || {
+        let mut results = Vec::new();
+        let mut report = BuildReport::default();
+
+        // Stops taking new files as soon as any fails, as the build will be abandoned
+        while !failed.load(Ordering::Relaxed) {
+            let index = next.fetch_add(1, Ordering::Relaxed);
+            let Some(path) = paths.get(index) else {
+                break;
+            };
+
+            let result = fs::File::open(path)
+                .map_err(|x| anyhow::anyhow!("Couldn't read {path:?} with error {x}"))
+                .and_then(|file| {
+                    write_chunks(file, algorithm, chunk_dir, &mut report)
+                        .with_context(|| format!("Couldn't store {}", path.display()))
+                });
+            failed.fetch_or(result.is_err(), Ordering::Relaxed);
+            results.push((index, result));
+        }
 
-    // Read initial permissions first
-    let mut perms = fs::metadata(&chunk_path)?.permissions();
+        (results, report)
+    };
+
+    let workers = std::thread::scope(|scope| {
+        let handles: Vec<_> = (0..workers.clamp(1, paths.len().max(1)))
+            .map(|_| scope.spawn(worker))
+            .collect();
+
+        handles
+            .into_iter()
+            .map(|handle| {
+                handle
+                    .join()
+                    .map_err(|_| anyhow::anyhow!("A build worker panicked"))
+            })
+            .collect::<Result<Vec<_>>>()
+    })?;
+
+    let mut results = Vec::new();
+    let mut report = BuildReport::default();
+    for (worker_results, worker_report) in workers {
+        results.extend(worker_results);
+        report.add(&worker_report);
+    }
 
-    // Probably not a good idea to hardcode this, but it's a sensible default.
-    perms.set_mode(0o755);
-    fs::set_permissions(&chunk_path, perms)?;
-    Ok(())
+    results.sort_by_key(|(index, _)| *index);
+    let written = results
+        .into_iter()
+        .map(|(_, result)| result)
+        .collect::<Result<_>>()?;
+
+    Ok((written, report))
 }
 
-#[cfg(feature = "decoding")]
-fn install_chunk(chunk_hash: &String, store: &Store) -> Result<()> {
-    use crate::compression::decompress_file;
+// Compresses a file into the repo's chunks as it's read, splitting it if it's large.
+// Only a chunk or two of it is ever in memory, however large the file is.
+#[cfg(feature = "encoding")]
+fn write_chunks(
+    file: impl std::io::Read,
+    algorithm: HashAlgorithm,
+    chunk_dir: &Path,
+    report: &mut BuildReport,
+) -> Result<WrittenFile> {
+    let mut chunker = chunking::Chunker::new(file);
+    let mut hasher = hash::Hasher::new(algorithm);
+    let mut size = 0;
+    let mut chunks = Vec::new();
+
+    while let Some(piece) = chunker.next_chunk()? {
+        hasher.update(&piece);
+        size += piece.len() as u64;
+
+        let chunk_hash = hash::hash(algorithm, &piece);
+        let compressed_size = write_chunk(&piece, &chunk_hash, chunk_dir, report)?;
+        chunks.push((chunk_hash, compressed_size));
+    }
 
-    let repo_chunk_path = resolve_repo_path(store, &format!("chunks/{chunk_hash}"))
-        .with_context(|| format!("Couldn't find chunk {chunk_hash}"))?;
-    let store_chunk_path = store.path.join("chunks").join(chunk_hash);
+    // A file stored as a single chunk is named by its own hash, which a lone chunk's already is.
+    // Empty files have no chunks, so get an empty one.
+    let hash = hasher.finish();
+    if chunks.is_empty() {
+        chunks.push((hash.clone(), write_chunk(&[], &hash, chunk_dir, report)?));
+    }
 
-    let mut repo_chunk = fs::read(repo_chunk_path)?;
-    let decompressed_chunk = decompress_file(&mut repo_chunk);
+    Ok(WrittenFile { hash, size, chunks })
+}
 
-    // Verify hash
-    let hash = hash::hash(&decompressed_chunk);
-    if &hash != chunk_hash {
-        bail!("Unable to verify hash")
+// Compresses and writes a single chunk, unless the repo already has it.
+// Returns the chunk's compressed size.
+#[cfg(feature = "encoding")]
+fn write_chunk(data: &[u8], hash: &str, chunk_dir: &Path, report: &mut BuildReport) -> Result<u64> {
+    let size = data.len() as u64;
+    let chunk_path = chunk_dir.join(hash);
+
+    if let Ok(metadata) = fs::metadata(&chunk_path) {
+        report.reused_chunks += 1;
+        report.reused_bytes += size;
+        return Ok(metadata.len());
     }
 
-    fs::write(store_chunk_path, decompressed_chunk)?;
+    let compressed = compression::compress_file(data, 3);
+
+    // Another worker, or another builder sharing the repo, may have written the same chunk since it was checked
+    if atomic::write_new(&chunk_path, &compressed)? {
+        report.new_chunks += 1;
+        report.new_bytes += size;
+    } else {
+        report.reused_chunks += 1;
+        report.reused_bytes += size;
+    }
+
+    Ok(compressed.len() as u64)
+}
+
+/// Installs an Artifact by name.
+///
+/// The manifest is checked against its hash before anything is installed, so a repo can't change an artifact's
+/// contents without changing its hash.
+/// Manifests in older formats are upgraded as they're read, and any format newer than this version knows fails with
+/// [`InstallError::UnsupportedManifestFormat`].
+///
+/// Every path to a hard linked file links to the same object, so they share an inode through the artifact.
+/// Subdirectories of artifacts built with trees are installed into the Store once, and linked to from every artifact
+/// sharing them, unless they contain hard links.
+///
+/// Modes and modification times from the manifest are always applied. Ownership is only applied when running as root,
+/// otherwise everything is owned by the installing user, setuid/setgid bits are dropped, and only `user.` xattrs are set.
+/// Device nodes can't be created at all without root, so artifacts containing them fail with
+/// [`InstallError::Unprivileged`] before anything is installed.
+///
+/// # Arguments
+///
+/// * `artifact_name` - The name of the artifact to install.
+/// * `store` - The correlated Store struct.
+///
+/// # Errors
+/// Returns an error if the artifact does not exist, or if any file operations fail.
+#[cfg(feature = "decoding")]
+pub fn install_artifact(artifact_name: &String, store: &Store) -> Result<()> {
+    install_artifact_version(artifact_name, &VersionSelector::Latest, store)
+}
+
+/// Installs a specific version of an Artifact, such as an older build, see [`list_versions`].
+///
+/// It's installed in place of whichever version is installed, in the same way as [`install_artifact`].
+/// It comes from the first of the Store's repos with that version, even if an earlier repo has others.
+///
+/// # Arguments
+///
+/// * `artifact_name` - The name of the artifact to install.
+/// * `selector` - Which of the artifact's versions to install.
+/// * `store` - The correlated Store struct.
+///
+/// # Errors
+/// Returns an error if the artifact has no such version, or if any file operations fail.
+#[cfg(feature = "decoding")]
+pub fn install_artifact_version(
+    artifact_name: &String,
+    selector: &VersionSelector,
+    store: &Store,
+) -> Result<()> {
+    use std::fs::{create_dir_all, rename};
+    use std::os::unix::fs::symlink;
+
+    let store_manifest_dir = store.path.join("manifests");
+    let store_artifacts_path = store.path.join("artifacts");
+
+    let (manifest_hash, manifest_bytes, tree::Flattened { manifest, trees }) =
+        read_artifact_manifest(artifact_name, selector, store)?;
+
+    let privileged = rustix::process::geteuid().is_root();
+    require_privileges(&manifest, privileged)?;
+
+    // Subtrees already in the Store are linked to as they are, along with everything inside them
+    let shared = install_trees(&manifest, &trees, privileged, store)?;
+    let entries: Vec<&Entry> = manifest
+        .entries
+        .iter()
+        .filter(|entry| !in_shared_tree(&entry.path, &shared))
+        .collect();
+
+    // Seperate to ensure objects have been installed prior to linked
+    let artifact_path = store_manifest_dir.join(&manifest_hash);
+    create_dir_all(&artifact_path)?;
+    let root = paths::Root::open(&artifact_path)?;
+    let files = files_by_path(&manifest);
+
+    for entry in &entries {
+        match shared.get(&entry.path) {
+            Some(tree_path) => root.symlink(tree_path, &entry.path)?,
+            None => install_entry(&root, &entry.path, entry, &files, privileged, store)?,
+        }
+    }
+    set_entry_attributes(
+        &root,
+        entries
+            .into_iter()
+            .filter(|entry| !shared.contains_key(&entry.path))
+            .map(|entry| (entry.path.as_str(), entry)),
+        privileged,
+    )?;
+
+    // Kept alongside the artifact, so it can be inspected later
+    write_installed_manifest(&manifest_bytes, &manifest_hash, store)?;
+
+    // Create a temporary symlink for atomic update
+    let tmp_file_name = get_temp_file(None, &store_artifacts_path);
+
+    let tmp_symlink = store_artifacts_path.join(&tmp_file_name);
+    let final_symlink = store_artifacts_path.join(artifact_name);
+
+    symlink(store_manifest_dir.join(&manifest_hash), &tmp_symlink)?;
+    rename(&tmp_symlink, &final_symlink)?;
 
     Ok(())
 }
 
-#[cfg(test)]
-mod tests {
-    use std::{env::temp_dir, fs::remove_dir_all};
+// Fetches an artifact's manifest from the repos, with its trees read back into a flat manifest.
+// Everything is checked before it's returned, as the manifest may come from an untrusted repo.
+// Also returns the manifest as it was written to the repo.
+#[cfg(feature = "decoding")]
+fn read_artifact_manifest(
+    artifact_name: &str,
+    selector: &VersionSelector,
+    store: &Store,
+) -> Result<(String, Vec<u8>, tree::Flattened)> {
+    let (_, _, manifest_hash) = find_index(store, artifact_name, selector)?
+        .ok_or_else(|| anyhow::anyhow!("Tried to get a manifest that didn't exist"))?;
+    let manifest_bytes = read_manifest_bytes(&manifest_hash, store)?;
+    let manifest = Manifest::decode_verified(&manifest_bytes, &manifest_hash)?;
+
+    // Trees are read back into a single flat manifest, remembering which files came from which tree
+    let flattened = read_trees(manifest, store)?;
+    flattened.manifest.validate()?;
+
+    Ok((manifest_hash, manifest_bytes, flattened))
+}
+
+// Fetches a manifest from whichever repo has it, checked against its hash
+#[cfg(feature = "decoding")]
+fn read_manifest(manifest_hash: &str, store: &Store) -> Result<Manifest> {
+    Manifest::decode_verified(&read_manifest_bytes(manifest_hash, store)?, manifest_hash)
+}
+
+// Fetches a manifest from whichever repo has it, as it was written there. Nothing is checked but its name.
+#[cfg(feature = "decoding")]
+fn read_manifest_bytes(manifest_hash: &str, store: &Store) -> Result<Vec<u8>> {
+    HashAlgorithm::of(manifest_hash)?;
+
+    Ok(fs::read(resolve_repo_path(
+        store,
+        &format!("manifests/{manifest_hash}"),
+    )?)?)
+}
+
+/// What installing an artifact would take, from [`plan_install`].
+#[cfg(feature = "decoding")]
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct InstallPlan {
+    /// Size of every file in the artifact, counting files that share an object once. This is the disk it would use.
+    pub total_bytes: u64,
+    /// How much of `total_bytes` is already in the Store, so wouldn't be written again.
+    pub present_bytes: u64,
+    /// Compressed size of the chunks that would be downloaded, as they're in neither the Store nor the cache.
+    pub fetch_bytes: u64,
+}
+
+/// Works out how much installing an artifact would download and write, without installing anything.
+///
+/// Only the manifest (and its trees) are fetched.
+///
+/// # Arguments
+///
+/// * `artifact_name` - The name of the artifact to plan for.
+/// * `store` - The correlated Store struct.
+///
+/// # Errors
+///
+/// Returns an error if the artifact does not exist, its manifest is invalid, or it was built before sizes were
+/// recorded.
+#[cfg(feature = "decoding")]
+pub fn plan_install(artifact_name: &str, store: &Store) -> Result<InstallPlan> {
+    use std::collections::HashSet;
+
+    let (_, _, tree::Flattened { manifest, .. }) =
+        read_artifact_manifest(artifact_name, &VersionSelector::Latest, store)?;
+    let privileged = rustix::process::geteuid().is_root();
+
+    let mut plan = InstallPlan::default();
+    let mut objects = HashSet::new();
+    let mut fetched = HashSet::new();
+
+    for entry in &manifest.entries {
+        let EntryKind::File { hash } = &entry.kind else {
+            continue;
+        };
+        let size = *manifest.sizes.get(hash).ok_or_else(|| {
+            anyhow::anyhow!("Manifest doesn't record the size of {:?}", entry.path)
+        })?;
+
+        // Every path to the same object only takes up space once
+        let object_path = object_path(&store.path, hash, &entry.attributes, privileged);
+        if !objects.insert(object_path.clone()) {
+            continue;
+        }
+        plan.total_bytes += size;
+
+        // Any object of the same content is copied, rather than fetched, see `install_object`
+        if object_path.exists() || has_object(&store.path, hash) {
+            plan.present_bytes += size;
+            continue;
+        }
+
+        let chunk_hashes = manifest
+            .chunks
+            .get(hash)
+            .map_or(std::slice::from_ref(hash), Vec::as_slice);
+        for chunk_hash in chunk_hashes {
+            if !fetched.insert(chunk_hash)
+                || store.cache_path.join("chunks").join(chunk_hash).exists()
+            {
+                continue;
+            }
+            plan.fetch_bytes += manifest.chunk_sizes.get(chunk_hash).ok_or_else(|| {
+                anyhow::anyhow!("Manifest doesn't record the size of chunk {chunk_hash}")
+            })?;
+        }
+    }
+
+    Ok(plan)
+}
+
+/// Lists every build of an artifact, oldest first, so an earlier one can be installed with [`install_artifact_version`].
+///
+/// They're listed from the first of the Store's repos that has the artifact, which is the one it's installed from.
+///
+/// # Arguments
+///
+/// * `artifact_name` - The name of the artifact.
+/// * `store` - The correlated Store struct.
+///
+/// # Errors
+///
+/// Returns an error if a repo's artifacts index can't be fetched, or an [`IndexError`] if it's invalid.
+/// An artifact that was never built has no versions.
+#[cfg(feature = "decoding")]
+pub fn list_versions(artifact_name: &str, store: &Store) -> Result<Vec<ArtifactVersion>> {
+    match find_index(store, artifact_name, &VersionSelector::Latest)? {
+        Some((_, index, _)) => artifacts::get_versions(artifact_name, &index),
+        None => Ok(Vec::new()),
+    }
+}
+
+/// Lists the channels of an artifact, alongside the manifest hash each points to, from the first of the Store's repos
+/// that has the artifact.
+///
+/// # Arguments
+///
+/// * `artifact_name` - The name of the artifact.
+/// * `store` - The correlated Store struct.
+///
+/// # Errors
+///
+/// Returns an error if a repo's artifacts index can't be fetched, or an [`IndexError`] if it's invalid.
+#[cfg(feature = "decoding")]
+pub fn list_channels(
+    artifact_name: &str,
+    store: &Store,
+) -> Result<std::collections::BTreeMap<String, String>> {
+    match find_index(store, artifact_name, &VersionSelector::Latest)? {
+        Some((_, index, _)) => artifacts::get_channels(artifact_name, &index),
+        None => Ok(std::collections::BTreeMap::new()),
+    }
+}
+
+// Whether the Store has an object of this content, with any attributes
+#[cfg(feature = "decoding")]
+fn has_object(store_path: &Path, file_hash: &str) -> bool {
+    fs::read_dir(store_path.join("objects").join(file_hash)).is_ok_and(|objects| {
+        objects
+            .flatten()
+            .any(|f| !f.file_name().to_string_lossy().starts_with(".tmp_"))
+    })
+}
+
+/// Reads the metadata of an installed artifact, as recorded when it was built.
+///
+/// # Arguments
+///
+/// * `artifact_name` - The name of the installed artifact.
+/// * `store` - The correlated Store struct.
+///
+/// # Errors
+///
+/// Returns an error if the artifact isn't installed, or was installed by a version that didn't keep its manifest.
+#[cfg(feature = "decoding")]
+pub fn artifact_metadata(artifact_name: &str, store: &Store) -> Result<Metadata> {
+    Ok(installed_manifest(artifact_name, store)?.metadata)
+}
+
+// Keeps the manifest an artifact was installed from in the Store, exactly as it was in the repo.
+// Manifests built with trees only keep the root tree's hash, so it's never much bigger than the metadata.
+// Written under a temporary name first, so it's never seen half written.
+#[cfg(feature = "decoding")]
+fn write_installed_manifest(
+    manifest_bytes: &[u8],
+    manifest_hash: &str,
+    store: &Store,
+) -> Result<()> {
+    let installed_dir = store.path.join("installed");
+    create_dir_all(&installed_dir)?;
+
+    let tmp_path = get_temp_file(None, &installed_dir);
+    fs::write(&tmp_path, manifest_bytes)?;
+    fs::rename(tmp_path, installed_dir.join(manifest_hash))?;
+
+    Ok(())
+}
+
+// Reads the manifest an installed artifact was installed from, see `write_installed_manifest`
+#[cfg(feature = "decoding")]
+fn installed_manifest(artifact_name: &str, store: &Store) -> Result<Manifest> {
+    if artifact_name.contains('/') || artifact_name == "." || artifact_name == ".." {
+        bail!("Invalid artifact name {artifact_name:?}");
+    }
+
+    let target = fs::read_link(store.path.join("artifacts").join(artifact_name))
+        .with_context(|| format!("Artifact {artifact_name:?} isn't installed"))?;
+    let Some(manifest_hash) = target.file_name().and_then(std::ffi::OsStr::to_str) else {
+        bail!("Artifact {artifact_name:?} doesn't link to a manifest");
+    };
+    HashAlgorithm::of(manifest_hash)?;
+
+    let manifest = fs::read(store.path.join("installed").join(manifest_hash))
+        .with_context(|| format!("Artifact {artifact_name:?} has no installed manifest"))?;
+
+    // Decoded like any manifest from a repo, so it's checked against its hash too
+    Manifest::decode_verified(&manifest, manifest_hash)
+}
+
+// Reads a manifest's trees back into a flat manifest, alongside the files directly inside each tree.
+// Flat manifests are returned as they are, with no trees.
+#[cfg(feature = "decoding")]
+fn read_trees(manifest: Manifest, store: &Store) -> Result<tree::Flattened> {
+    let Some(root) = manifest.root.clone() else {
+        return Ok(tree::Flattened {
+            manifest,
+            trees: Vec::new(),
+        });
+    };
+
+    if !manifest.entries.is_empty() || !manifest.chunks.is_empty() {
+        bail!("Manifest has both a root tree and entries");
+    }
+
+    let mut flattened = tree::flatten(&root, &mut |hash| {
+        Ok(fs::read(resolve_repo_path(
+            store,
+            &format!("trees/{hash}"),
+        )?)?)
+    })?;
+    flattened.manifest.metadata = manifest.metadata;
+
+    Ok(flattened)
+}
+
+// Installs the object of every file in the manifest, and every subtree that can be shared into the Store's trees.
+// A subtree that's already there is skipped whole, so updating to an artifact sharing it does nothing for it.
+// Returns where every shared subtree is, by its path in the artifact.
+#[cfg(feature = "decoding")]
+fn install_trees(
+    manifest: &Manifest,
+    trees: &[tree::FlatTree],
+    privileged: bool,
+    store: &Store,
+) -> Result<HashMap<String, PathBuf>> {
+    let mut shared = HashMap::new();
+
+    // Flat manifests have no trees to share
+    if trees.is_empty() {
+        install_objects(manifest, 0..manifest.entries.len(), privileged, store)?;
+        return Ok(shared);
+    }
+
+    let directories: HashMap<&str, &Entry> = manifest
+        .entries
+        .iter()
+        .filter(|entry| entry.kind == EntryKind::Directory)
+        .map(|entry| (entry.path.as_str(), entry))
+        .collect();
+    // Paths of the trees that are installed into the artifact itself
+    let mut unshared = std::collections::HashSet::new();
+
+    // Every subtree comes before the tree containing it, so is already installed when its parent is
+    for tree in trees {
+        // Hard links point anywhere in the artifact, so a tree with them isn't the same everywhere it's used.
+        // The root has no attributes of its own, and always changes anyway.
+        let directory = directories.get(tree.path.as_str());
+        let shareable = tree.entries.iter().all(|&index| {
+            let entry = &manifest.entries[index];
+            !matches!(entry.kind, EntryKind::Hardlink { .. }) && !unshared.contains(&entry.path)
+        });
+        let (Some(directory), true) = (directory, shareable) else {
+            install_objects(manifest, tree.entries.iter().copied(), privileged, store)?;
+            unshared.insert(tree.path.clone());
+            continue;
+        };
+
+        let tree_path = tree_path(&store.path, &tree.hash, &directory.attributes, privileged);
+        if !tree_path.exists() {
+            install_tree(manifest, tree, &tree_path, &shared, privileged, store)?;
+        }
+        shared.insert(tree.path.clone(), tree_path);
+    }
+
+    Ok(shared)
+}
+
+// Installs a shared tree into the Store at `tree_path`, linking to its subtrees in `shared`.
+// Prepared under a temporary name, so a tree is never seen half installed.
+#[cfg(feature = "decoding")]
+fn install_tree(
+    manifest: &Manifest,
+    tree: &tree::FlatTree,
+    tree_path: &Path,
+    shared: &HashMap<String, PathBuf>,
+    privileged: bool,
+    store: &Store,
+) -> Result<()> {
+    let tree_dir = tree_path
+        .parent()
+        .ok_or_else(|| anyhow::anyhow!("Failed to get parent directory"))?;
+    create_dir_all(tree_dir)?;
+    let tmp_path = get_temp_file(None, tree_dir);
+    fs::create_dir(&tmp_path)?;
+
+    install_objects(manifest, tree.entries.iter().copied(), privileged, store)?;
+
+    let root = paths::Root::open(&tmp_path)?;
+    let files = files_by_path(manifest);
+    // Entries are named relative to the tree, rather than the artifact
+    let entries: Vec<(&str, &Entry)> = tree
+        .entries
+        .iter()
+        .map(|&index| {
+            let entry = &manifest.entries[index];
+            let name = entry.path.rsplit('/').next().unwrap_or_default();
+            (name, entry)
+        })
+        .collect();
+
+    for (name, entry) in &entries {
+        match shared.get(&entry.path) {
+            Some(subtree_path) => root.symlink(subtree_path, name)?,
+            None => install_entry(&root, name, entry, &files, privileged, store)?,
+        }
+    }
+    set_entry_attributes(
+        &root,
+        entries
+            .into_iter()
+            .filter(|(_, entry)| !shared.contains_key(&entry.path)),
+        privileged,
+    )?;
+
+    // The tree's own attributes are those of the directory it's for
+    let Some(directory) = manifest
+        .entries
+        .iter()
+        .find(|entry| entry.path == tree.path)
+    else {
+        bail!("Tree {} has no directory", tree.hash);
+    };
+    let tmp_name = tmp_path
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+    paths::Root::open(tree_dir)?.set_attributes(&tmp_name, &directory.attributes, privileged)?;
+
+    // Another install may have finished the same tree in the meantime, which is just as good
+    if let Err(e) = fs::rename(&tmp_path, tree_path) {
+        let _ = fs::remove_dir_all(&tmp_path);
+        if !tree_path.exists() {
+            return Err(e.into());
+        }
+    }
+
+    Ok(())
+}
+
+// Whether `path` is inside one of the `shared` trees, so is installed along with it
+#[cfg(feature = "decoding")]
+fn in_shared_tree(path: &str, shared: &HashMap<String, PathBuf>) -> bool {
+    path.match_indices('/')
+        .any(|(index, _)| index > 0 && shared.contains_key(&path[..index]))
+}
+
+// Every file in the manifest, by path, as hard links share their file's object, which has the same attributes
+#[cfg(feature = "decoding")]
+fn files_by_path(manifest: &Manifest) -> HashMap<&str, &String> {
+    manifest
+        .entries
+        .iter()
+        .filter_map(|entry| match &entry.kind {
+            EntryKind::File { hash } => Some((entry.path.as_str(), hash)),
+            _ => None,
+        })
+        .collect()
+}
+
+// Creates a single entry at `path` under `root`, linking files to their objects
+#[cfg(feature = "decoding")]
+fn install_entry(
+    root: &paths::Root,
+    path: &str,
+    entry: &Entry,
+    files: &HashMap<&str, &String>,
+    privileged: bool,
+    store: &Store,
+) -> Result<()> {
+    use rustix::fs::{FileType, makedev};
+
+    match &entry.kind {
+        EntryKind::File { hash } => {
+            let object_path = object_path(&store.path, hash, &entry.attributes, privileged);
+            root.symlink(&object_path, path)?;
+        }
+        EntryKind::Hardlink { target } => {
+            let hash = files
+                .get(target.as_str())
+                .ok_or_else(|| anyhow::anyhow!("Hard link target {target:?} is missing"))?;
+            let object_path = object_path(&store.path, hash, &entry.attributes, privileged);
+            root.symlink(&object_path, path)?;
+        }
+        EntryKind::Symlink { target } => root.symlink(Path::new(target), path)?,
+        EntryKind::Directory => root.create_dir(path)?,
+        EntryKind::CharDevice { major, minor } => {
+            root.create_special(path, FileType::CharacterDevice, makedev(*major, *minor))?;
+        }
+        EntryKind::BlockDevice { major, minor } => {
+            root.create_special(path, FileType::BlockDevice, makedev(*major, *minor))?;
+        }
+        EntryKind::Fifo => root.create_special(path, FileType::Fifo, 0)?,
+        EntryKind::Socket => root.create_special(path, FileType::Socket, 0)?,
+        EntryKind::Tree { .. } => bail!("{:?} is a tree, which wasn't read", entry.path),
+    }
+
+    Ok(())
+}
+
+// Applies the attributes of every entry at its path under `root`.
+// Done last, and deepest first, so a read-only directory can't block anything inside it.
+// Files and hard links are skipped, as their attributes are on their objects.
+#[cfg(feature = "decoding")]
+fn set_entry_attributes<'a>(
+    root: &paths::Root,
+    entries: impl IntoIterator<Item = (&'a str, &'a Entry)>,
+    privileged: bool,
+) -> Result<()> {
+    let mut entries: Vec<_> = entries
+        .into_iter()
+        .filter(|(_, entry)| {
+            !matches!(
+                entry.kind,
+                EntryKind::File { .. } | EntryKind::Hardlink { .. }
+            )
+        })
+        .collect();
+    entries.sort_by_key(|(path, _)| std::cmp::Reverse(path.matches('/').count()));
+
+    for (path, entry) in entries {
+        root.set_attributes(path, &entry.attributes, privileged)?;
+    }
+
+    Ok(())
+}
+
+// Installs the object of every file among the entries at `indices`
+#[cfg(feature = "decoding")]
+fn install_objects(
+    manifest: &Manifest,
+    indices: impl IntoIterator<Item = usize>,
+    privileged: bool,
+    store: &Store,
+) -> Result<()> {
+    for index in indices {
+        let entry = &manifest.entries[index];
+        let EntryKind::File { hash } = &entry.kind else {
+            continue;
+        };
+
+        install_object(
+            hash,
+            manifest.chunks.get(hash),
+            &entry.attributes,
+            privileged,
+            store,
+        )?;
+    }
+
+    Ok(())
+}
+
+// Device nodes can only be created as root, so an unprivileged install fails before anything is written
+#[cfg(feature = "decoding")]
+fn require_privileges(manifest: &Manifest, privileged: bool) -> Result<()> {
+    if privileged {
+        return Ok(());
+    }
+
+    if let Some(entry) = manifest.entries.iter().find(|entry| entry.kind.is_device()) {
+        return Err(InstallError::Unprivileged {
+            path: entry.path.clone(),
+        }
+        .into());
+    }
+
+    Ok(())
+}
+
+// Fetches a repo's artifacts index. Unlike everything else in a repo it changes whenever something is built, so it's
+// never read from the cache.
+#[cfg(feature = "decoding")]
+fn fetch_index(store: &Store, repo: &str) -> Result<PathBuf> {
+    match store.kind {
+        RepoType::Local => Ok(PathBuf::from(repo).join("artifacts")),
+        RepoType::Https => {
+            // Each repo has its own index, so they're cached by repo
+            let index_dir = store.cache_path.join("indexes");
+            create_dir_all(&index_dir)?;
+            let index = index_dir.join(hash::hash(HashAlgorithm::Xxh3, repo.as_bytes()));
+
+            network::download_file(&repo_url(repo, "artifacts"), &index)?;
+            Ok(index)
+        }
+    }
+}
+
+// Finds the first repo with the selected build of an artifact, in the order they're listed, alongside its artifacts
+// index and the build's manifest hash. A repo with other builds of the artifact, but not that one, is passed over.
+#[cfg(feature = "decoding")]
+fn find_index<'a>(
+    store: &'a Store,
+    artifact_name: &str,
+    selector: &VersionSelector,
+) -> Result<Option<(&'a str, PathBuf, String)>> {
+    // List of all errors accumulated in the next for loop, only returned if no repo has the artifact
+    let mut error_list = vec![];
+
+    for repo in &store.repos {
+        let index = match fetch_index(store, repo) {
+            Ok(index) => index,
+            Err(e) => {
+                error_list.push(e);
+                continue;
+            }
+        };
+
+        if let Some(manifest_hash) = artifacts::get_artifact(artifact_name, selector, &index)? {
+            return Ok(Some((repo, index, manifest_hash)));
+        }
+    }
+
+    if error_list.is_empty() {
+        Ok(None)
+    } else {
+        Err(anyhow::anyhow!("{:?}", error_list))
+    }
+}
+
+// The URL of a file in an `Https` repo
+#[cfg(feature = "decoding")]
+fn repo_url(repo: &str, path: &str) -> String {
+    format!("{}/{path}", repo.trim_end_matches('/'))
+}
+
+#[cfg(feature = "decoding")]
+fn resolve_repo_path(store: &Store, path: &String) -> Result<PathBuf> {
+    if store.cache_path.join(path).exists() {
+        return Ok(store.cache_path.join(path));
+    }
+
+    let joined_path = store.cache_path.join(path);
+    let parent = joined_path
+        .parent()
+        .ok_or_else(|| anyhow::anyhow!("Failed to get parent directory"))?;
+    create_dir_all(parent)?;
+
+    // List of all errors accumulated in the next for loop.
+    let mut error_list = vec![];
+
+    for repo in &store.repos {
+        let result = match store.kind {
+            RepoType::Https => {
+                network::download_file(&repo_url(repo, path), &store.cache_path.join(path))
+                    .map(|_| ())
+            }
+            RepoType::Local => {
+                fs::copy(PathBuf::from(&repo).join(path), store.cache_path.join(path))
+                    .map(|_| ())
+                    .map_err(|e| anyhow::anyhow!(e))
+            }
+        };
+
+        if result.is_ok() {
+            return Ok(store.cache_path.join(path));
+        }
+
+        // If error, just add to `error_list`` and continue to next repo, do not return error
+        error_list.push(result.unwrap_err());
+    }
+
+    Err(anyhow::anyhow!("{:?}", error_list))
+}
+
+#[cfg(feature = "decoding")]
+fn get_temp_file(potential: Option<u8>, dir: &Path) -> PathBuf {
+    let potential = potential.unwrap_or_default();
+
+    let file_name = format!(".tmp_{potential}");
+
+    // Overflow protection, if the potential number is too high, we clear the oldest old temp file and replace it.
+    if potential > u8::MAX - 1 {
+        // Find the most recently modified temp file and remove it to prevent overflow
+        if let Ok(entries) = std::fs::read_dir(dir) {
+            let oldest_temp = entries
+                .flatten()
+                .filter(|f| {
+                    f.file_type().is_ok_and(|ft| ft.is_file())
+                        && f.file_name().to_string_lossy().starts_with(".tmp_")
+                })
+                .min_by_key(|f| f.metadata().and_then(|m| m.modified()).ok())
+                .map(|f| f.path())
+                .unwrap();
+
+            fs::remove_file(&oldest_temp).unwrap_or_else(|x| {
+                panic!("Failed to remove old temp symlink: {x}");
+            });
+
+            return oldest_temp;
+        }
+    }
+
+    // Check if the file already exists, if it does, increment the potential number
+    if dir.join(&file_name).exists() {
+        get_temp_file(Some(potential + 1), dir)
+    } else {
+        dir.join(&file_name)
+    }
+}
+
+// Finds where a file's object lives in the Store.
+// Every path linked to an object shares its mode, owner and mtime, so objects are keyed by both content and the
+// attributes actually applied, rather than content alone.
+#[cfg(feature = "decoding")]
+fn object_path(
+    store_path: &Path,
+    file_hash: &str,
+    attributes: &manifest::Attributes,
+    privileged: bool,
+) -> PathBuf {
+    store_path
+        .join("objects")
+        .join(file_hash)
+        .join(attributes_name(attributes, privileged))
+}
+
+// Finds where a shared tree lives in the Store, keyed by its directory's attributes like an object
+#[cfg(feature = "decoding")]
+fn tree_path(
+    store_path: &Path,
+    tree_hash: &str,
+    attributes: &manifest::Attributes,
+    privileged: bool,
+) -> PathBuf {
+    store_path
+        .join("trees")
+        .join(tree_hash)
+        .join(attributes_name(attributes, privileged))
+}
+
+// A file name for the attributes actually applied to an object or tree
+#[cfg(feature = "decoding")]
+fn attributes_name(attributes: &manifest::Attributes, privileged: bool) -> String {
+    use rustix::process::{getegid, geteuid};
+
+    let (uid, gid) = if privileged {
+        (attributes.uid, attributes.gid)
+    } else {
+        (geteuid().as_raw(), getegid().as_raw())
+    };
+
+    let mut name = format!(
+        "{:o}_{uid}_{gid}_{}",
+        attributes.effective_mode(privileged),
+        attributes.mtime
+    );
+
+    // Xattrs are too long for a file name, so a hash of them is used instead.
+    // BLAKE3, so an untrusted repo can't craft xattrs that collide with those of another artifact.
+    let mut xattrs = attributes.effective_xattrs(privileged).peekable();
+    if xattrs.peek().is_some() {
+        let mut hasher = hash::Hasher::new(HashAlgorithm::Blake3);
+        for (xattr_name, value) in xattrs {
+            hasher.update(xattr_name.as_bytes());
+            hasher.update(&[0]);
+            hasher.update(&value.len().to_le_bytes());
+            hasher.update(value);
+        }
+        name += "_";
+        name += &hasher.finish();
+    }
+
+    name
+}
+
+// Installs a file into the Store as an object with the given attributes, unless an identical one already exists.
+#[cfg(feature = "decoding")]
+fn install_object(
+    file_hash: &String,
+    chunks: Option<&Vec<String>>,
+    attributes: &manifest::Attributes,
+    privileged: bool,
+    store: &Store,
+) -> Result<()> {
+    let object_path = object_path(&store.path, file_hash, attributes, privileged);
+    if object_path.exists() {
+        return Ok(());
+    }
+
+    let object_dir = object_path
+        .parent()
+        .ok_or_else(|| anyhow::anyhow!("Failed to get parent directory"))?;
+    create_dir_all(object_dir)?;
+
+    // Prepared under a temporary name, so an object never exists with the wrong content or attributes
+    let tmp_path = get_temp_file(None, object_dir);
+
+    // Copies another object of the same content where possible, rather than fetching and reassembling it again
+    let copied = fs::read_dir(object_dir)?
+        .flatten()
+        .filter(|f| !f.file_name().to_string_lossy().starts_with(".tmp_"))
+        .any(|f| fs::copy(f.path(), &tmp_path).is_ok());
+    if !copied {
+        write_file(file_hash, chunks, store, &tmp_path)?;
+    }
+
+    set_object_attributes(&tmp_path, attributes, privileged)?;
+    fs::rename(tmp_path, object_path)?;
+
+    Ok(())
+}
+
+// Applies an entry's mode, owner and modification time to an object
+#[cfg(feature = "decoding")]
+fn set_object_attributes(
+    object_path: &Path,
+    attributes: &manifest::Attributes,
+    privileged: bool,
+) -> Result<()> {
+    use rustix::fs::{AtFlags, CWD, Gid, Mode, Uid, chmod, chown, utimensat};
+
+    // Changing the owner clears setuid and setgid, so the mode has to be applied afterwards
+    if privileged {
+        chown(
+            object_path,
+            Some(Uid::from_raw(attributes.uid)),
+            Some(Gid::from_raw(attributes.gid)),
+        )?;
+    }
+    // After the owner, as changing it clears `security.capability`, but before the mode, which could stop the
+    // installing user from writing to the object
+    xattrs::apply_path(object_path, attributes.effective_xattrs(privileged))?;
+    chmod(
+        object_path,
+        Mode::from_raw_mode(attributes.effective_mode(privileged)),
+    )?;
+    utimensat(
+        CWD,
+        object_path,
+        &paths::timestamps(attributes.mtime),
+        AtFlags::empty(),
+    )?;
+
+    Ok(())
+}
+
+// Writes a file's content to `path`, reassembling it first if it was split into several chunks.
+#[cfg(feature = "decoding")]
+fn write_file(
+    file_hash: &String,
+    chunks: Option<&Vec<String>>,
+    store: &Store,
+    path: &Path,
+) -> Result<()> {
+    use std::io::Write;
+
+    let Some(chunks) = chunks else {
+        fs::write(path, read_chunk(file_hash, store)?)?;
+        return Ok(());
+    };
+
+    let mut file = std::io::BufWriter::new(fs::File::create(path)?);
+    let mut hasher = hash::Hasher::new(HashAlgorithm::of(file_hash)?);
+
+    for chunk_hash in chunks {
+        let chunk = read_chunk(chunk_hash, store)?;
+        hasher.update(&chunk);
+        file.write_all(&chunk)?;
+    }
+    file.flush()?;
+
+    // A failed verification never leaves a bad object behind
+    if &hasher.finish() != file_hash {
+        fs::remove_file(path)?;
+        bail!("Unable to verify hash")
+    }
+
+    Ok(())
+}
+
+// Fetches a single chunk from the repos, and returns it decompressed and verified.
+#[cfg(feature = "decoding")]
+fn read_chunk(chunk_hash: &String, store: &Store) -> Result<Vec<u8>> {
+    use crate::compression::try_decompress;
+
+    let repo_chunk_path = resolve_repo_path(store, &format!("chunks/{chunk_hash}"))
+        .with_context(|| format!("Couldn't find chunk {chunk_hash}"))?;
+
+    let repo_chunk = fs::read(repo_chunk_path)?;
+    let decompressed_chunk = try_decompress(&repo_chunk)
+        .with_context(|| format!("Couldn't decompress chunk {chunk_hash}"))?;
+
+    // Verify hash, with whichever algorithm the repo named the chunk with
+    let hash = hash::hash(HashAlgorithm::of(chunk_hash)?, &decompressed_chunk);
+    if &hash != chunk_hash {
+        bail!("Unable to verify hash")
+    }
+
+    Ok(decompressed_chunk)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env::temp_dir, fs::remove_dir_all};
+
+    use std::fs;
+    use std::fs::File;
+    use std::os::unix::fs::PermissionsExt;
+
+    #[cfg(feature = "encoding")]
+    use crate::create_repo;
+
+    #[cfg(all(feature = "encoding", feature = "decoding"))]
+    use crate::{RepoType, Store, create_store, resolve_repo_path};
+
+    #[test]
+    #[cfg(all(feature = "encoding", feature = "decoding"))]
+    fn test_create_store() {
+        let _ = create_test_store("basic");
+    }
+
+    #[cfg(all(feature = "encoding", feature = "decoding"))]
+    fn create_test_store(test_name: &str) -> Store {
+        let repo = temp_dir().join(format!("lcas_testing_repo_{test_name}"));
+        let cache = temp_dir().join(format!("lcas_testing_cache_{test_name}"));
+        let store_path = temp_dir().join(format!("lcas_testing_store_{test_name}"));
+
+        let _ = remove_dir_all(&repo);
+        let _ = remove_dir_all(&cache);
+        let _ = remove_dir_all(&store_path);
+
+        let store = Store {
+            cache_path: cache,
+            kind: RepoType::Local,
+            path: store_path,
+            repos: vec![repo.to_string_lossy().to_string()],
+        };
+        create_repo(&repo).unwrap();
+        create_store(&store).unwrap();
+
+        store
+    }
+
+    #[test]
+    #[cfg(all(feature = "encoding", feature = "decoding"))]
+    fn test_store_to_cache_empty() {
+        let store = create_test_store("store_to_cache_empty");
+
+        assert!(resolve_repo_path(&store, &"manifests/undefined".to_string()).is_err());
+    }
+
+    #[test]
+    #[cfg(all(feature = "encoding", feature = "decoding"))]
+    fn test_create_store_when_exists_should_fail() {
+        let store = create_test_store("create_store_when_exists_should_fail");
+        // Try to create the store again, should error
+        let result = create_store(&store);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "encoding")]
+    fn test_create_repo_creates_directories() {
+        let repo = temp_dir().join("lcas_testing_repo_dirs");
+        let _ = remove_dir_all(&repo);
+
+        create_repo(&repo).unwrap();
+
+        assert!(repo.join("chunks").exists());
+        assert!(repo.join("manifests").exists());
+    }
+
+    #[test]
+    #[cfg(feature = "encoding")]
+    fn test_create_repo_on_file() {
+        let repo = temp_dir().join("lcas_testing_repo_file");
+        let _ = remove_dir_all(&repo);
+
+        fs::write(&repo, "Testing data.").unwrap();
+
+        let err = create_repo(&repo).unwrap_err();
+        assert!(err.to_string().contains("Already exists!"));
+    }
+
+    #[test]
+    #[cfg(feature = "encoding")]
+    fn test_create_repo_on_dir() {
+        let repo = temp_dir().join("lcas_testing_repo_dir");
+        let _ = remove_dir_all(&repo);
+
+        fs::create_dir(&repo).unwrap();
+
+        let err = create_repo(&repo).unwrap_err();
+        assert!(err.to_string().contains("Already exists!"));
+    }
+
+    #[test]
+    #[cfg(feature = "decoding")]
+    fn test_create_temp_file_overflow_test() {
+        let dir = temp_dir().join("lcas_temp_file_overflow_test");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+
+        for _i in 0..1000 {
+            let file_name = super::get_temp_file(None, &dir);
+            File::create_new(&file_name).unwrap();
+            assert!(file_name.exists());
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "decoding")]
+    fn test_get_temp_file_returns_unique_names() {
+        let dir = temp_dir().join("lcas_temp_file_unique_test");
+        let _ = std::fs::create_dir_all(&dir);
+        let file1 = super::get_temp_file(None, &dir);
+        std::fs::File::create(dir.join(&file1)).unwrap();
+        let file2 = super::get_temp_file(None, &dir);
+        assert_ne!(file1, file2);
+        let _ = remove_dir_all(&dir);
+    }
+
+    #[test]
+    #[cfg(feature = "decoding")]
+    fn test_set_object_attributes_sets_permissions() {
+        use std::os::unix::fs::MetadataExt;
+
+        let dir = temp_dir().join("lcas_executable_test");
+        let _ = fs::create_dir_all(&dir);
+        let object_path = dir.join("testobject");
+        File::create(&object_path).unwrap();
+
+        let attributes = crate::manifest::Attributes {
+            mode: 0o755,
+            uid: 0,
+            gid: 0,
+            mtime: 1_000_000_000,
+            xattrs: crate::manifest::Xattrs::new(),
+        };
+        super::set_object_attributes(&object_path, &attributes, false).unwrap();
+
+        let metadata = fs::metadata(&object_path).unwrap();
+        assert_eq!(metadata.permissions().mode() & 0o111, 0o111);
+        assert_eq!(metadata.mtime(), 1_000_000_000);
+
+        // Without any permissions, the object can't be opened again once its mode is applied
+        let unreadable = crate::manifest::Attributes {
+            mode: 0o000,
+            ..attributes
+        };
+        super::set_object_attributes(&object_path, &unreadable, false).unwrap();
+        assert_eq!(
+            fs::metadata(&object_path).unwrap().permissions().mode() & 0o7777,
+            0
+        );
+
+        let _ = remove_dir_all(&dir);
+    }
+
+    #[test]
+    #[cfg(all(feature = "encoding", feature = "decoding"))]
+    fn test_create_and_load_artifact() {
+        use std::path::PathBuf;
+
+        use crate::{build, install_artifact};
+
+        let store = create_test_store("artifact");
+        let input_dir = temp_dir().join("lcas_artifact_test");
+
+        let _ = fs::remove_dir_all(&input_dir);
+        fs::create_dir_all(&input_dir).unwrap();
+        fs::write(input_dir.join("file1.txt"), b"Hello, world!").unwrap();
+        fs::write(input_dir.join("file2.txt"), b"Another file.").unwrap();
+
+        // Create a nested directory and files inside it
+        let nested_dir = input_dir.join("nested");
+        fs::create_dir(&nested_dir).unwrap();
+        fs::write(nested_dir.join("nested1.txt"), b"Nested file 1.").unwrap();
+        fs::write(nested_dir.join("nested2.txt"), b"Nested file 2.").unwrap();
+
+        // Create a deeper nested directory
+        let deeper_nested_dir = nested_dir.join("deeper");
+        fs::create_dir(&deeper_nested_dir).unwrap();
+        fs::write(deeper_nested_dir.join("deepfile.txt"), b"Deep nested file.").unwrap();
+
+        build(
+            &input_dir,
+            &PathBuf::from(&store.repos.first().unwrap()),
+            "test_artifact",
+        )
+        .unwrap();
+
+        install_artifact(&"test_artifact".to_string(), &store).unwrap();
+    }
+
+    #[test]
+    #[cfg(all(feature = "encoding", feature = "decoding"))]
+    fn test_create_and_load_chunked_artifact() {
+        use std::path::PathBuf;
+
+        use crate::{build, install_artifact};
+
+        let store = create_test_store("chunked_artifact");
+        let input_dir = temp_dir().join("lcas_artifact_test_chunked");
+
+        // Large enough to be split, and not compressible into a single repeating pattern
+        let mut state: u32 = 1;
+        let large_file: Vec<u8> = (0..3 * 1024 * 1024)
+            .map(|_| {
+                state = state.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+                state.to_le_bytes()[3]
+            })
+            .collect();
+
+        let _ = fs::remove_dir_all(&input_dir);
+        fs::create_dir_all(&input_dir).unwrap();
+        fs::write(input_dir.join("large.img"), &large_file).unwrap();
+        // Files are read as they're chunked, so an empty one never produces a chunk by itself
+        fs::write(input_dir.join("empty"), b"").unwrap();
+
+        let repo = PathBuf::from(&store.repos.first().unwrap());
+        build(&input_dir, &repo, "chunked_artifact").unwrap();
+
+        // The file should have been stored as several chunks, rather than one
+        assert!(fs::read_dir(repo.join("chunks")).unwrap().count() > 1);
+
+        install_artifact(&"chunked_artifact".to_string(), &store).unwrap();
+
+        let installed = fs::read(store.path.join("artifacts/chunked_artifact/large.img")).unwrap();
+        assert_eq!(installed, large_file);
+        let empty = fs::read(store.path.join("artifacts/chunked_artifact/empty")).unwrap();
+        assert!(empty.is_empty());
+    }
+
+    #[test]
+    #[cfg(all(feature = "encoding", feature = "decoding"))]
+    fn test_create_and_load_artifact_blake3() {
+        use std::path::PathBuf;
+
+        use crate::{HashAlgorithm, build, create_repo_with_algorithm, install_artifact};
+
+        let store = create_test_store("artifact_blake3");
+        let repo = PathBuf::from(&store.repos.first().unwrap());
+        let input_dir = temp_dir().join("lcas_artifact_test_blake3");
+
+        // Recreate the repo with a cryptographic hash
+        remove_dir_all(&repo).unwrap();
+        create_repo_with_algorithm(&repo, HashAlgorithm::Blake3).unwrap();
+
+        let _ = fs::remove_dir_all(&input_dir);
+        fs::create_dir_all(&input_dir).unwrap();
+        fs::write(input_dir.join("file1.txt"), b"Hello, world!").unwrap();
+
+        let manifest_hash = build(&input_dir, &repo, "blake3_artifact").unwrap();
+        assert!(manifest_hash.starts_with("blake3-"));
+
+        install_artifact(&"blake3_artifact".to_string(), &store).unwrap();
+
+        let installed = fs::read(store.path.join("artifacts/blake3_artifact/file1.txt")).unwrap();
+        assert_eq!(installed, b"Hello, world!");
+    }
+
+    #[test]
+    #[cfg(all(feature = "encoding", feature = "decoding"))]
+    fn test_create_and_load_artifact_symlinks() {
+        use std::os::unix::ffi::OsStrExt;
+        use std::os::unix::fs::symlink;
+        use std::path::PathBuf;
+
+        use crate::{build, install_artifact};
+
+        let store = create_test_store("artifact_symlinks");
+        let input_dir = temp_dir().join("lcas_artifact_test_symlinks");
+
+        let _ = fs::remove_dir_all(&input_dir);
+        fs::create_dir_all(input_dir.join("usr/lib")).unwrap();
+        fs::write(
+            input_dir.join("usr/lib/libfoo.so"),
+            b"Not really a library.",
+        )
+        .unwrap();
+        symlink("usr/lib", input_dir.join("lib")).unwrap();
+        symlink("/etc/hostname", input_dir.join("hostname")).unwrap();
+        symlink("does/not/exist", input_dir.join("dangling")).unwrap();
+
+        build(
+            &input_dir,
+            &PathBuf::from(&store.repos.first().unwrap()),
+            "symlinks",
+        )
+        .unwrap();
+        install_artifact(&"symlinks".to_string(), &store).unwrap();
+
+        let artifact = store.path.join("artifacts/symlinks");
+        assert_eq!(
+            fs::read_link(artifact.join("lib")).unwrap(),
+            PathBuf::from("usr/lib")
+        );
+        assert_eq!(
+            fs::read_link(artifact.join("hostname")).unwrap(),
+            PathBuf::from("/etc/hostname")
+        );
+        assert_eq!(
+            fs::read_link(artifact.join("dangling")).unwrap(),
+            PathBuf::from("does/not/exist")
+        );
+        assert_eq!(
+            fs::read(artifact.join("lib/libfoo.so")).unwrap(),
+            b"Not really a library."
+        );
+
+        // A target that isn't UTF-8 can't be stored as-is, so fails the build rather than being mangled
+        let invalid = std::ffi::OsStr::from_bytes(b"lib\xff");
+        symlink(invalid, input_dir.join("invalid")).unwrap();
+        assert!(
+            build(
+                &input_dir,
+                &PathBuf::from(&store.repos.first().unwrap()),
+                "invalid_symlink",
+            )
+            .is_err()
+        );
+    }
+
+    #[test]
+    #[cfg(all(feature = "encoding", feature = "decoding"))]
+    fn test_create_and_load_artifact_empty_directories() {
+        use std::path::PathBuf;
+
+        use crate::{build, install_artifact};
+
+        let store = create_test_store("artifact_empty_directories");
+        let input_dir = temp_dir().join("lcas_artifact_test_empty_directories");
+
+        let _ = fs::remove_dir_all(&input_dir);
+        fs::create_dir_all(input_dir.join("var/lib/foo")).unwrap();
+        fs::create_dir_all(input_dir.join("plugins")).unwrap();
+        fs::create_dir_all(input_dir.join("private")).unwrap();
+        fs::write(input_dir.join("private/file.txt"), b"Secret.").unwrap();
+        fs::set_permissions(
+            input_dir.join("var/lib/foo"),
+            fs::Permissions::from_mode(0o750),
+        )
+        .unwrap();
+        fs::set_permissions(input_dir.join("private"), fs::Permissions::from_mode(0o500)).unwrap();
+
+        build(
+            &input_dir,
+            &PathBuf::from(&store.repos.first().unwrap()),
+            "empty_directories",
+        )
+        .unwrap();
+        install_artifact(&"empty_directories".to_string(), &store).unwrap();
+
+        let artifact = store.path.join("artifacts/empty_directories");
+        let mode = |path: &str| {
+            fs::metadata(artifact.join(path))
+                .unwrap()
+                .permissions()
+                .mode()
+        };
+
+        assert!(artifact.join("var/lib/foo").is_dir());
+        assert!(artifact.join("plugins").is_dir());
+        assert_eq!(mode("var/lib/foo") & 0o7777, 0o750);
+        assert_eq!(mode("private") & 0o7777, 0o500);
+        assert_eq!(
+            fs::read(artifact.join("private/file.txt")).unwrap(),
+            b"Secret."
+        );
+
+        // Allow the test directories to be cleaned up next time
+        fs::set_permissions(input_dir.join("private"), fs::Permissions::from_mode(0o700)).unwrap();
+        fs::set_permissions(artifact.join("private"), fs::Permissions::from_mode(0o700)).unwrap();
+    }
+
+    #[test]
+    #[cfg(all(feature = "encoding", feature = "decoding"))]
+    fn test_create_and_load_artifact_attributes() {
+        use std::os::unix::fs::{MetadataExt, symlink};
+        use std::path::PathBuf;
+        use std::time::{Duration, SystemTime};
+
+        use crate::{BuildOptions, Mtimes, build_with_options, install_artifact};
+
+        let store = create_test_store("artifact_attributes");
+        let input_dir = temp_dir().join("lcas_artifact_test_attributes");
+        let privileged = rustix::process::geteuid().is_root();
+        let mtime = SystemTime::UNIX_EPOCH + Duration::from_secs(1_234_567_890);
+
+        let _ = fs::remove_dir_all(&input_dir);
+        fs::create_dir_all(input_dir.join("private")).unwrap();
+        fs::write(input_dir.join("private/secret"), b"Hunter2").unwrap();
+        fs::write(input_dir.join("helper"), b"#!/bin/sh").unwrap();
+        symlink("helper", input_dir.join("link")).unwrap();
+
+        fs::set_permissions(
+            input_dir.join("private/secret"),
+            fs::Permissions::from_mode(0o600),
+        )
+        .unwrap();
+        fs::set_permissions(input_dir.join("helper"), fs::Permissions::from_mode(0o4755)).unwrap();
+        fs::set_permissions(input_dir.join("private"), fs::Permissions::from_mode(0o700)).unwrap();
+        File::options()
+            .write(true)
+            .open(input_dir.join("private/secret"))
+            .unwrap()
+            .set_modified(mtime)
+            .unwrap();
+        File::open(input_dir.join("private"))
+            .unwrap()
+            .set_modified(mtime)
+            .unwrap();
+        if privileged {
+            std::os::unix::fs::chown(input_dir.join("private/secret"), Some(1234), Some(5678))
+                .unwrap();
+            std::os::unix::fs::lchown(input_dir.join("link"), Some(1234), Some(5678)).unwrap();
+        }
+
+        let options = BuildOptions {
+            mtimes: Mtimes::Preserve,
+            ..BuildOptions::default()
+        };
+        build_with_options(
+            &input_dir,
+            &PathBuf::from(&store.repos.first().unwrap()),
+            "attributes",
+            &options,
+        )
+        .unwrap();
+        install_artifact(&"attributes".to_string(), &store).unwrap();
+
+        let artifact = store.path.join("artifacts/attributes");
+        let secret = fs::metadata(artifact.join("private/secret")).unwrap();
+        let private = fs::metadata(artifact.join("private")).unwrap();
+        let helper = fs::metadata(artifact.join("helper")).unwrap();
+
+        assert_eq!(secret.mode() & 0o7777, 0o600);
+        assert_eq!(secret.mtime(), 1_234_567_890);
+        assert_eq!(private.mode() & 0o7777, 0o700);
+        assert_eq!(private.mtime(), 1_234_567_890);
+
+        if privileged {
+            let link = fs::symlink_metadata(artifact.join("link")).unwrap();
+            assert_eq!((secret.uid(), secret.gid()), (1234, 5678));
+            assert_eq!((link.uid(), link.gid()), (1234, 5678));
+            assert_eq!(helper.mode() & 0o7777, 0o4755);
+        } else {
+            assert_eq!(helper.mode() & 0o7777, 0o755);
+        }
+    }
+
+    #[test]
+    #[cfg(all(feature = "encoding", feature = "decoding"))]
+    fn test_install_file_without_permissions() {
+        use std::path::PathBuf;
+
+        use crate::{BuildOptions, build, install_artifact, manifest::Manifest, write_manifest};
+
+        let store = create_test_store("no_permissions");
+        let repo = PathBuf::from(&store.repos.first().unwrap());
+        let input_dir = temp_dir().join("lcas_artifact_test_no_permissions");
+
+        let _ = fs::remove_dir_all(&input_dir);
+        fs::create_dir_all(&input_dir).unwrap();
+        fs::write(input_dir.join("locked"), b"Nobody can read this").unwrap();
+        let manifest_hash = build(&input_dir, &repo, "no_permissions").unwrap();
+
+        // Building can't read a file without permissions, so it's only taken away in the manifest
+        let mut manifest =
+            Manifest::decode(&fs::read(repo.join("manifests").join(manifest_hash)).unwrap())
+                .unwrap();
+        manifest.entries[0].attributes.mode = 0o000;
+        write_manifest(
+            manifest,
+            crate::HashAlgorithm::Xxh3,
+            &repo,
+            "no_permissions",
+            &BuildOptions::default(),
+        )
+        .unwrap();
+
+        install_artifact(&"no_permissions".to_string(), &store).unwrap();
+        let locked = fs::metadata(store.path.join("artifacts/no_permissions/locked")).unwrap();
+        assert_eq!(locked.permissions().mode() & 0o7777, 0);
+    }
+
+    #[test]
+    #[cfg(all(feature = "encoding", feature = "decoding"))]
+    fn test_create_and_load_artifact_xattrs() {
+        use std::path::PathBuf;
+
+        use crate::{BuildOptions, build, build_with_options, install_artifact};
+
+        let store = create_test_store("artifact_xattrs");
+        let repo = PathBuf::from(&store.repos.first().unwrap());
+        let input_dir = temp_dir().join("lcas_artifact_test_xattrs");
+
+        let _ = fs::remove_dir_all(&input_dir);
+        fs::create_dir_all(input_dir.join("dir")).unwrap();
+        fs::write(input_dir.join("dir/labelled"), b"Same content").unwrap();
+        fs::write(input_dir.join("plain"), b"Same content").unwrap();
+        rustix::fs::setxattr(
+            input_dir.join("dir/labelled"),
+            "user.label",
+            b"labelled",
+            rustix::fs::XattrFlags::empty(),
+        )
+        .unwrap();
+        rustix::fs::setxattr(
+            input_dir.join("dir"),
+            "user.label",
+            b"dir",
+            rustix::fs::XattrFlags::empty(),
+        )
+        .unwrap();
+
+        let options = BuildOptions {
+            xattr_namespaces: vec!["user".to_string()],
+            ..BuildOptions::default()
+        };
+        let with_xattrs = build_with_options(&input_dir, &repo, "xattrs", &options)
+            .unwrap()
+            .manifest_hash;
+        // Nothing is captured unless asked for
+        assert_ne!(build(&input_dir, &repo, "no_xattrs").unwrap(), with_xattrs);
+
+        install_artifact(&"xattrs".to_string(), &store).unwrap();
+
+        let artifact = store.path.join("artifacts/xattrs");
+        let label = |path: &str| {
+            let mut value = [0; 64];
+            let len = rustix::fs::getxattr(artifact.join(path), "user.label", &mut value[..]);
+            len.map(|len| value[..len].to_vec())
+        };
+        assert_eq!(label("dir/labelled").unwrap(), b"labelled");
+        assert_eq!(label("dir").unwrap(), b"dir");
+        // Shares its content with `dir/labelled`, but not its object
+        assert!(label("plain").is_err());
+    }
+
+    #[test]
+    #[cfg(all(feature = "encoding", feature = "decoding"))]
+    fn test_create_and_load_artifact_hardlinks() {
+        use std::os::unix::fs::MetadataExt;
+        use std::path::PathBuf;
+
+        use crate::manifest::{EntryKind, Manifest};
+        use crate::{build, install_artifact};
+
+        let store = create_test_store("artifact_hardlinks");
+        let repo = PathBuf::from(&store.repos.first().unwrap());
+        let input_dir = temp_dir().join("lcas_artifact_test_hardlinks");
+
+        let _ = fs::remove_dir_all(&input_dir);
+        fs::create_dir_all(input_dir.join("bin")).unwrap();
+        fs::write(input_dir.join("bin/gzip"), b"Same content").unwrap();
+        fs::hard_link(input_dir.join("bin/gzip"), input_dir.join("bin/gunzip")).unwrap();
+
+        let manifest_hash = build(&input_dir, &repo, "hardlinks").unwrap();
+        let manifest =
+            Manifest::decode(&fs::read(repo.join("manifests").join(manifest_hash)).unwrap())
+                .unwrap();
+        let hardlinks = manifest
+            .entries
+            .iter()
+            .filter(|entry| matches!(entry.kind, EntryKind::Hardlink { .. }))
+            .count();
+        assert_eq!(hardlinks, 1);
+
+        install_artifact(&"hardlinks".to_string(), &store).unwrap();
+
+        let artifact = store.path.join("artifacts/hardlinks");
+        let inode = |path: &str| fs::metadata(artifact.join(path)).unwrap().ino();
+        assert_eq!(inode("bin/gzip"), inode("bin/gunzip"));
+        assert_eq!(
+            fs::read(artifact.join("bin/gunzip")).unwrap(),
+            b"Same content"
+        );
+    }
+
+    #[test]
+    #[cfg(all(feature = "encoding", feature = "decoding"))]
+    fn test_create_and_load_artifact_special_files() {
+        use std::os::unix::fs::FileTypeExt;
+        use std::path::PathBuf;
+
+        use rustix::fs::{FileType, Mode, makedev, mknodat};
+
+        use crate::{build, install_artifact};
+
+        let store = create_test_store("artifact_special_files");
+        let repo = PathBuf::from(&store.repos.first().unwrap());
+        let input_dir = temp_dir().join("lcas_artifact_test_special_files");
+        let privileged = rustix::process::geteuid().is_root();
+
+        let _ = fs::remove_dir_all(&input_dir);
+        fs::create_dir_all(input_dir.join("dev")).unwrap();
+        let mknod = |path: &str, file_type, dev| {
+            let path = input_dir.join(path);
+            mknodat(rustix::fs::CWD, &path, file_type, Mode::empty(), dev).unwrap();
+            fs::set_permissions(path, fs::Permissions::from_mode(0o620)).unwrap();
+        };
+        mknod("dev/initctl", FileType::Fifo, 0);
+        if privileged {
+            mknod("dev/null", FileType::CharacterDevice, makedev(1, 3));
+            mknod("dev/loop0", FileType::BlockDevice, makedev(7, 0));
+        }
+
+        build(&input_dir, &repo, "special_files").unwrap();
+        install_artifact(&"special_files".to_string(), &store).unwrap();
+
+        let artifact = store.path.join("artifacts/special_files");
+        let metadata = |path: &str| fs::symlink_metadata(artifact.join(path)).unwrap();
+        assert!(metadata("dev/initctl").file_type().is_fifo());
+        assert_eq!(metadata("dev/initctl").permissions().mode() & 0o7777, 0o620);
 
-    use std::fs;
-    use std::fs::File;
-    use std::os::unix::fs::PermissionsExt;
+        if privileged {
+            use std::os::unix::fs::MetadataExt;
 
-    #[cfg(feature = "encoding")]
-    use crate::create_repo;
+            assert!(metadata("dev/null").file_type().is_char_device());
+            assert_eq!(metadata("dev/null").rdev(), makedev(1, 3));
+            assert!(metadata("dev/loop0").file_type().is_block_device());
+            assert_eq!(metadata("dev/loop0").rdev(), makedev(7, 0));
+        }
+    }
 
+    #[test]
     #[cfg(feature = "decoding")]
-    use crate::{RepoType, Store, create_store, resolve_repo_path};
+    fn test_require_privileges_for_devices() {
+        use crate::InstallError;
+        use crate::manifest::{Attributes, Entry, EntryKind, Manifest, Xattrs};
+
+        let entry = |path: &str, kind| Entry {
+            path: path.to_string(),
+            kind,
+            attributes: Attributes {
+                mode: 0o666,
+                uid: 0,
+                gid: 0,
+                mtime: 0,
+                xattrs: Xattrs::new(),
+            },
+        };
+        let manifest = Manifest::new(vec![
+            entry("/dev/initctl", EntryKind::Fifo),
+            entry("/dev/null", EntryKind::CharDevice { major: 1, minor: 3 }),
+        ]);
+
+        assert!(super::require_privileges(&manifest, true).is_ok());
+        let error = super::require_privileges(&manifest, false).unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<InstallError>(),
+            Some(&InstallError::Unprivileged {
+                path: "/dev/null".to_string()
+            })
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "encoding")]
+    fn test_parallel_build_matches_serial() {
+        use crate::{BuildOptions, build_with_options};
+
+        let input_dir = temp_dir().join("lcas_artifact_test_parallel");
+        let _ = fs::remove_dir_all(&input_dir);
+        for i in 0..64 {
+            let dir = input_dir.join(format!("dir{}", i % 4));
+            fs::create_dir_all(&dir).unwrap();
+            fs::write(dir.join(format!("file{i}")), format!("File number {i}")).unwrap();
+        }
+        // Large enough to be split, so the chunk list is covered too
+        let large: Vec<u8> = (0..4 * 1024 * 1024_u32)
+            .map(|i| i.wrapping_mul(2_654_435_761).to_le_bytes()[3])
+            .collect();
+        fs::write(input_dir.join("large"), large).unwrap();
+
+        let build_with = |workers: usize| {
+            let repo = temp_dir().join(format!("lcas_testing_repo_parallel_{workers}"));
+            let _ = remove_dir_all(&repo);
+            create_repo(&repo).unwrap();
+
+            let options = BuildOptions {
+                workers,
+                ..BuildOptions::default()
+            };
+            let manifest_hash = build_with_options(&input_dir, &repo, "parallel", &options)
+                .unwrap()
+                .manifest_hash;
+            fs::read(repo.join("manifests").join(manifest_hash)).unwrap()
+        };
+
+        assert_eq!(build_with(1), build_with(8));
+    }
 
     #[test]
     #[cfg(all(feature = "encoding", feature = "decoding"))]
-    fn test_create_store() {
-        let _ = create_test_store("basic");
+    fn test_build_is_deterministic() {
+        use crate::build;
+
+        // The same tree, created in two different orders
+        let create_tree = |name: &str, reversed: bool| {
+            let input_dir = temp_dir().join(name);
+            let _ = fs::remove_dir_all(&input_dir);
+
+            let mut names: Vec<_> = (0..32).map(|i| format!("entry{i}")).collect();
+            if reversed {
+                names.reverse();
+            }
+            for name in names {
+                fs::create_dir_all(input_dir.join(&name)).unwrap();
+                fs::write(input_dir.join(&name).join("file"), &name).unwrap();
+                fs::write(input_dir.join(format!("{name}.txt")), &name).unwrap();
+            }
+
+            input_dir
+        };
+        let forwards = create_tree("lcas_artifact_test_deterministic_forwards", false);
+        let backwards = create_tree("lcas_artifact_test_deterministic_backwards", true);
+
+        let repo = temp_dir().join("lcas_testing_repo_deterministic");
+        let _ = remove_dir_all(&repo);
+        create_repo(&repo).unwrap();
+
+        // Modification times are normalised, so they don't have to match
+        File::open(forwards.join("entry0.txt"))
+            .unwrap()
+            .set_modified(std::time::SystemTime::UNIX_EPOCH)
+            .unwrap();
+
+        let forwards_hash = build(&forwards, &repo, "forwards").unwrap();
+        assert_eq!(
+            forwards_hash,
+            build(&backwards, &repo, "backwards").unwrap()
+        );
+        // A trailing slash doesn't change any paths
+        let with_slash = std::path::PathBuf::from(format!("{}/", forwards.display()));
+        assert_eq!(
+            forwards_hash,
+            build(&with_slash, &repo, "with_slash").unwrap()
+        );
+
+        let manifest = crate::manifest::Manifest::decode(
+            &fs::read(repo.join("manifests").join(&forwards_hash)).unwrap(),
+        )
+        .unwrap();
+        let paths: Vec<_> = manifest
+            .entries
+            .iter()
+            .map(|entry| entry.path.as_str())
+            .collect();
+        assert_eq!(paths[..3], ["/entry0", "/entry0/file", "/entry0.txt"]);
     }
 
+    #[test]
     #[cfg(all(feature = "encoding", feature = "decoding"))]
-    fn create_test_store(test_name: &str) -> Store {
-        let repo = temp_dir().join(format!("lcas_testing_repo_{test_name}"));
-        let cache = temp_dir().join(format!("lcas_testing_cache_{test_name}"));
-        let store_path = temp_dir().join(format!("lcas_testing_store_{test_name}"));
+    fn test_build_mtimes() {
+        use crate::{BuildOptions, Mtimes, build_with_options, manifest::Manifest};
+        use std::time::{Duration, SystemTime};
+
+        let input_dir = temp_dir().join("lcas_artifact_test_mtimes");
+        let _ = fs::remove_dir_all(&input_dir);
+        fs::create_dir_all(&input_dir).unwrap();
+        for (name, mtime) in [("old", 1_000), ("new", 2_000_000_000)] {
+            fs::write(input_dir.join(name), name).unwrap();
+            File::open(input_dir.join(name))
+                .unwrap()
+                .set_modified(SystemTime::UNIX_EPOCH + Duration::from_secs(mtime))
+                .unwrap();
+        }
 
+        let repo = temp_dir().join("lcas_testing_repo_mtimes");
         let _ = remove_dir_all(&repo);
-        let _ = remove_dir_all(&cache);
-        let _ = remove_dir_all(&store_path);
+        create_repo(&repo).unwrap();
 
-        let store = Store {
-            cache_path: cache,
-            kind: RepoType::Local,
-            path: store_path,
-            repos: vec![repo.to_string_lossy().to_string()],
+        let mtimes = |mtimes: Mtimes| {
+            let options = BuildOptions {
+                mtimes,
+                ..BuildOptions::default()
+            };
+            let hash = build_with_options(&input_dir, &repo, "mtimes", &options)
+                .unwrap()
+                .manifest_hash;
+            Manifest::decode(&fs::read(repo.join("manifests").join(hash)).unwrap())
+                .unwrap()
+                .entries
+                .into_iter()
+                .map(|entry| entry.attributes.mtime)
+                .collect::<Vec<_>>()
         };
+
+        // Sorted by name, so "new" comes first
+        assert_eq!(mtimes(Mtimes::Fixed(0)), [0, 0]);
+        assert_eq!(mtimes(Mtimes::Fixed(42)), [42, 42]);
+        assert_eq!(mtimes(Mtimes::Clamp(1_700_000_000)), [1_700_000_000, 1_000]);
+        assert_eq!(mtimes(Mtimes::Preserve), [2_000_000_000, 1_000]);
+        assert_eq!(mtimes(BuildOptions::default().mtimes), [0, 0]);
+    }
+
+    #[test]
+    #[cfg(feature = "encoding")]
+    fn test_remove_artifact_report() {
+        use std::collections::BTreeSet;
+
+        use crate::{BuildOptions, build, build_with_options, remove_artifact_with_report};
+
+        let repo = temp_dir().join("lcas_testing_repo_remove_report");
+        let input_dir = temp_dir().join("lcas_artifact_test_remove_report");
+        let hash = |data: &[u8]| crate::hash::hash(crate::HashAlgorithm::Xxh3, data);
+        let _ = remove_dir_all(&repo);
+        let _ = fs::remove_dir_all(&input_dir);
         create_repo(&repo).unwrap();
-        create_store(&store).unwrap();
+        fs::create_dir_all(input_dir.join("dir")).unwrap();
+        fs::write(input_dir.join("dir/shared"), b"Shared").unwrap();
+        fs::write(input_dir.join("dir/own"), b"Own").unwrap();
 
-        store
+        let trees = BuildOptions {
+            trees: true,
+            ..BuildOptions::default()
+        };
+        let removed = build_with_options(&input_dir, &repo, "removed", &trees).unwrap();
+        fs::remove_file(input_dir.join("dir/own")).unwrap();
+        build(&input_dir, &repo, "kept").unwrap();
+
+        // Only needs the manifests and trees in the repo, so works without `decoding` too
+        let unreferenced = remove_artifact_with_report(&repo, "removed").unwrap();
+        assert_eq!(
+            unreferenced.manifests,
+            BTreeSet::from([removed.manifest_hash])
+        );
+        assert_eq!(
+            unreferenced.trees.len(),
+            fs::read_dir(repo.join("trees")).unwrap().count()
+        );
+        assert_eq!(unreferenced.chunks, BTreeSet::from([hash(b"Own")]));
+    }
+
+    #[test]
+    #[cfg(feature = "encoding")]
+    fn test_rebuild_reuses_chunks() {
+        use crate::{BuildOptions, build_with_options};
+
+        let repo = temp_dir().join("lcas_testing_repo_reuse");
+        let input_dir = temp_dir().join("lcas_artifact_test_reuse");
+        let _ = remove_dir_all(&repo);
+        let _ = fs::remove_dir_all(&input_dir);
+        create_repo(&repo).unwrap();
+        fs::create_dir_all(&input_dir).unwrap();
+        fs::write(input_dir.join("a"), b"First file").unwrap();
+        fs::write(input_dir.join("b"), b"Same").unwrap();
+        fs::write(input_dir.join("c"), b"Same").unwrap();
+
+        let options = BuildOptions::default();
+        let first = build_with_options(&input_dir, &repo, "reuse", &options).unwrap();
+        assert_eq!(first.new_chunks, 2);
+        assert_eq!(first.new_bytes, 14);
+        assert_eq!(first.reused_chunks, 1);
+        assert_eq!(first.reused_bytes, 4);
+
+        fs::write(input_dir.join("d"), b"New file").unwrap();
+        let modified = fs::metadata(repo.join("chunks"))
+            .unwrap()
+            .modified()
+            .unwrap();
+        let second = build_with_options(&input_dir, &repo, "reuse", &options).unwrap();
+        assert_eq!(second.new_chunks, 1);
+        assert_eq!(second.new_bytes, 8);
+        assert_eq!(second.reused_chunks, 3);
+        assert_eq!(second.reused_bytes, 18);
+        assert_ne!(second.manifest_hash, first.manifest_hash);
+        assert!(
+            fs::metadata(repo.join("chunks"))
+                .unwrap()
+                .modified()
+                .unwrap()
+                >= modified
+        );
     }
 
     #[test]
     #[cfg(all(feature = "encoding", feature = "decoding"))]
-    fn test_store_to_cache_empty() {
-        let store = create_test_store("store_to_cache_empty");
+    fn test_create_and_load_artifact_trees() {
+        use std::path::PathBuf;
 
-        assert!(resolve_repo_path(&store, &"manifests/undefined".to_string()).is_err());
+        use crate::{BuildOptions, build, build_with_options, install_artifact};
+
+        let store = create_test_store("artifact_trees");
+        let repo = PathBuf::from(&store.repos.first().unwrap());
+        let input_dir = temp_dir().join("lcas_artifact_test_trees");
+
+        let _ = fs::remove_dir_all(&input_dir);
+        fs::create_dir_all(input_dir.join("usr/share/doc")).unwrap();
+        fs::write(input_dir.join("usr/share/doc/readme"), b"Shared").unwrap();
+        fs::write(input_dir.join("version"), b"1").unwrap();
+        fs::hard_link(input_dir.join("version"), input_dir.join("usr/version")).unwrap();
+        fs::create_dir_all(input_dir.join("etc")).unwrap();
+        fs::write(input_dir.join("etc/a"), b"Linked").unwrap();
+        fs::hard_link(input_dir.join("etc/a"), input_dir.join("etc/b")).unwrap();
+
+        let options = BuildOptions {
+            trees: true,
+            ..BuildOptions::default()
+        };
+        let first = build_with_options(&input_dir, &repo, "first", &options).unwrap();
+        let trees = fs::read_dir(repo.join("trees")).unwrap().count();
+        // The root, `etc`, `usr`, `usr/share` and `usr/share/doc`
+        assert_eq!(trees, 5);
+
+        fs::remove_file(input_dir.join("usr/version")).unwrap();
+        fs::write(input_dir.join("version"), b"2").unwrap();
+        build_with_options(&input_dir, &repo, "second", &options).unwrap();
+        // Only the root and `usr` changed, so `usr/share` and everything in it is shared
+        assert_eq!(fs::read_dir(repo.join("trees")).unwrap().count(), trees + 2);
+
+        // The same tree as a flat manifest has a different hash, but the same content
+        let flat = build(&input_dir, &repo, "flat").unwrap();
+        assert_ne!(flat, first.manifest_hash);
+
+        for name in ["first", "second", "flat"] {
+            install_artifact(&name.to_string(), &store).unwrap();
+        }
+
+        let artifacts = store.path.join("artifacts");
+        assert_eq!(fs::read(artifacts.join("first/usr/version")).unwrap(), b"1");
+        assert!(!artifacts.join("second/usr/version").exists());
+        for name in ["first", "second", "flat"] {
+            assert_eq!(
+                fs::read(artifacts.join(name).join("usr/share/doc/readme")).unwrap(),
+                b"Shared"
+            );
+        }
+        // Each tree is only installed once, no matter how many artifacts share it, and linked to from each of them.
+        // `etc` has a hard link in it, which could point anywhere, so it's installed into each artifact instead.
+        assert_eq!(fs::read_dir(store.path.join("trees")).unwrap().count(), 4);
+        for name in ["first", "second"] {
+            let artifact = artifacts.join(name);
+            assert!(
+                fs::symlink_metadata(artifact.join("usr"))
+                    .unwrap()
+                    .is_symlink()
+            );
+            assert!(fs::symlink_metadata(artifact.join("etc")).unwrap().is_dir());
+            assert_eq!(fs::read(artifact.join("etc/b")).unwrap(), b"Linked");
+        }
+        assert_eq!(
+            fs::canonicalize(artifacts.join("first/usr/share")).unwrap(),
+            fs::canonicalize(artifacts.join("second/usr/share")).unwrap()
+        );
     }
 
     #[test]
     #[cfg(all(feature = "encoding", feature = "decoding"))]
-    fn test_create_store_when_exists_should_fail() {
-        let store = create_test_store("create_store_when_exists_should_fail");
-        // Try to create the store again, should error
-        let result = create_store(&store);
-        assert!(result.is_err());
+    fn test_artifact_metadata() {
+        use std::path::PathBuf;
+
+        use crate::{
+            BuildOptions, Metadata, artifact_metadata, build_with_options, install_artifact,
+        };
+
+        let store = create_test_store("artifact_metadata");
+        let repo = PathBuf::from(&store.repos.first().unwrap());
+        let input_dir = temp_dir().join("lcas_artifact_test_metadata");
+
+        let _ = fs::remove_dir_all(&input_dir);
+        fs::create_dir_all(input_dir.join("bin")).unwrap();
+        fs::write(input_dir.join("bin/tool"), b"#!/bin/sh").unwrap();
+
+        let metadata = Metadata {
+            commit: Some("0123abc".to_string()),
+            job: Some("ci-42".to_string()),
+            build_time: Some(1_700_000_000),
+            toolchain: Some("rustc 1.90.0".to_string()),
+            extra: [("branch".to_string(), "main".to_string())].into(),
+        };
+        let plain = build_with_options(&input_dir, &repo, "plain", &BuildOptions::default())
+            .unwrap()
+            .manifest_hash;
+        for trees in [false, true] {
+            let options = BuildOptions {
+                metadata: metadata.clone(),
+                trees,
+                ..BuildOptions::default()
+            };
+            let report = build_with_options(&input_dir, &repo, "described", &options).unwrap();
+            assert_ne!(report.manifest_hash, plain);
+
+            install_artifact(&"described".to_string(), &store).unwrap();
+            assert_eq!(artifact_metadata("described", &store).unwrap(), metadata);
+            // The Store keeps the manifest as it was in the repo, rather than a copy of every entry
+            assert_eq!(
+                fs::read(store.path.join("installed").join(&report.manifest_hash)).unwrap(),
+                fs::read(repo.join("manifests").join(&report.manifest_hash)).unwrap()
+            );
+        }
+
+        install_artifact(&"plain".to_string(), &store).unwrap();
+        assert!(artifact_metadata("plain", &store).unwrap().is_empty());
+        // It's checked against its hash like any other manifest
+        fs::write(store.path.join("installed").join(&plain), b"{}").unwrap();
+        assert!(artifact_metadata("plain", &store).is_err());
+        assert!(artifact_metadata("missing", &store).is_err());
+        assert!(artifact_metadata("../artifacts/plain", &store).is_err());
     }
 
     #[test]
-    #[cfg(feature = "encoding")]
-    fn test_create_repo_creates_directories() {
-        let repo = temp_dir().join("lcas_testing_repo_dirs");
-        let _ = remove_dir_all(&repo);
+    #[cfg(all(feature = "encoding", feature = "decoding"))]
+    fn test_plan_install() {
+        use std::path::PathBuf;
 
-        create_repo(&repo).unwrap();
+        use crate::{InstallPlan, build, install_artifact, plan_install};
 
-        assert!(repo.join("chunks").exists());
-        assert!(repo.join("manifests").exists());
+        let store = create_test_store("plan_install");
+        let repo = PathBuf::from(&store.repos.first().unwrap());
+        let input_dir = temp_dir().join("lcas_artifact_test_plan_install");
+
+        // Large enough to be split
+        let large: Vec<u8> = (0..3 * 1024 * 1024_u32)
+            .map(|i| i.wrapping_mul(2_654_435_761).to_le_bytes()[3])
+            .collect();
+        let _ = fs::remove_dir_all(&input_dir);
+        fs::create_dir_all(&input_dir).unwrap();
+        fs::write(input_dir.join("large"), &large).unwrap();
+        fs::write(input_dir.join("small"), b"Hello, world!").unwrap();
+        fs::hard_link(input_dir.join("small"), input_dir.join("linked")).unwrap();
+        build(&input_dir, &repo, "first").unwrap();
+
+        let repo_bytes: u64 = fs::read_dir(repo.join("chunks"))
+            .unwrap()
+            .map(|chunk| chunk.unwrap().metadata().unwrap().len())
+            .sum();
+        let total_bytes = large.len() as u64 + 13;
+        assert_eq!(
+            plan_install("first", &store).unwrap(),
+            InstallPlan {
+                total_bytes,
+                present_bytes: 0,
+                fetch_bytes: repo_bytes,
+            }
+        );
+
+        install_artifact(&"first".to_string(), &store).unwrap();
+        assert_eq!(
+            plan_install("first", &store).unwrap(),
+            InstallPlan {
+                total_bytes,
+                present_bytes: total_bytes,
+                fetch_bytes: 0,
+            }
+        );
+
+        // Only the new file has to be fetched, even though `small` now needs a new object
+        fs::write(input_dir.join("new"), b"New").unwrap();
+        fs::set_permissions(input_dir.join("small"), fs::Permissions::from_mode(0o600)).unwrap();
+        build(&input_dir, &repo, "second").unwrap();
+
+        let new_chunk = crate::hash::hash(crate::HashAlgorithm::Xxh3, b"New");
+        assert_eq!(
+            plan_install("second", &store).unwrap(),
+            InstallPlan {
+                total_bytes: total_bytes + 3,
+                present_bytes: total_bytes,
+                fetch_bytes: fs::metadata(repo.join("chunks").join(new_chunk))
+                    .unwrap()
+                    .len(),
+            }
+        );
     }
 
     #[test]
-    #[cfg(feature = "encoding")]
-    fn test_create_repo_on_file() {
-        let repo = temp_dir().join("lcas_testing_repo_file");
-        let _ = remove_dir_all(&repo);
+    #[cfg(all(feature = "encoding", feature = "decoding"))]
+    fn test_install_older_versions() {
+        use std::path::PathBuf;
 
-        fs::write(&repo, "Testing data.").unwrap();
+        use crate::{
+            BuildOptions, VersionSelector, build_with_options, install_artifact,
+            install_artifact_version, list_versions,
+        };
 
-        let err = create_repo(&repo).unwrap_err();
-        assert!(err.to_string().contains("Already exists!"));
+        let store = create_test_store("older_versions");
+        let repo = PathBuf::from(&store.repos.first().unwrap());
+        let input_dir = temp_dir().join("lcas_artifact_test_older_versions");
+        let name = "versioned".to_string();
+
+        let _ = fs::remove_dir_all(&input_dir);
+        fs::create_dir_all(&input_dir).unwrap();
+        for version in ["1.0", "2.0", "3.0"] {
+            fs::write(input_dir.join("version"), version).unwrap();
+            let options = BuildOptions {
+                version: Some(version.to_string()),
+                ..BuildOptions::default()
+            };
+            build_with_options(&input_dir, &repo, &name, &options).unwrap();
+        }
+
+        let versions = list_versions(&name, &store).unwrap();
+        assert_eq!(
+            versions
+                .iter()
+                .map(|version| version.version.as_deref().unwrap())
+                .collect::<Vec<_>>(),
+            vec!["1.0", "2.0", "3.0"]
+        );
+        assert!(versions.iter().all(|version| version.timestamp.is_some()));
+        assert!(list_versions("unknown", &store).unwrap().is_empty());
+
+        let installed =
+            || fs::read_to_string(store.path.join("artifacts/versioned/version")).unwrap();
+        install_artifact(&name, &store).unwrap();
+        assert_eq!(installed(), "3.0");
+
+        install_artifact_version(&name, &VersionSelector::Version("1.0".to_string()), &store)
+            .unwrap();
+        assert_eq!(installed(), "1.0");
+
+        let selector = VersionSelector::ManifestHash(versions[1].manifest_hash.clone());
+        install_artifact_version(&name, &selector, &store).unwrap();
+        assert_eq!(installed(), "2.0");
+
+        assert!(
+            install_artifact_version(&name, &VersionSelector::Version("4.0".to_string()), &store)
+                .is_err()
+        );
+        assert!(
+            build_with_options(&input_dir, &repo, "bad/name", &BuildOptions::default()).is_err()
+        );
     }
 
     #[test]
-    #[cfg(feature = "encoding")]
-    fn test_create_repo_on_dir() {
-        let repo = temp_dir().join("lcas_testing_repo_dir");
-        let _ = remove_dir_all(&repo);
+    #[cfg(all(feature = "encoding", feature = "decoding"))]
+    fn test_install_from_channels() {
+        use std::collections::BTreeMap;
+        use std::path::PathBuf;
 
-        fs::create_dir(&repo).unwrap();
+        use crate::{
+            BuildOptions, VersionSelector, build_with_options, install_artifact_version,
+            list_channels, promote,
+        };
 
-        let err = create_repo(&repo).unwrap_err();
-        assert!(err.to_string().contains("Already exists!"));
+        let store = create_test_store("channels");
+        let repo = PathBuf::from(&store.repos.first().unwrap());
+        let input_dir = temp_dir().join("lcas_artifact_test_channels");
+        let name = "channelled".to_string();
+        let options = BuildOptions {
+            channels: vec!["nightly".to_string()],
+            ..BuildOptions::default()
+        };
+
+        let _ = fs::remove_dir_all(&input_dir);
+        fs::create_dir_all(&input_dir).unwrap();
+        fs::write(input_dir.join("build"), "first").unwrap();
+        let first = build_with_options(&input_dir, &repo, &name, &options).unwrap();
+        assert_eq!(
+            promote(&repo, &name, "nightly", "stable").unwrap(),
+            first.manifest_hash
+        );
+        fs::write(input_dir.join("build"), "second").unwrap();
+        let second = build_with_options(&input_dir, &repo, &name, &options).unwrap();
+
+        assert!(promote(&repo, &name, "beta", "stable").is_err());
+        assert!(promote(&repo, &name, "nightly", "bad/channel").is_err());
+        let bad = BuildOptions {
+            channels: vec![String::new()],
+            ..BuildOptions::default()
+        };
+        assert!(build_with_options(&input_dir, &repo, &name, &bad).is_err());
+
+        assert_eq!(
+            list_channels(&name, &store).unwrap(),
+            BTreeMap::from([
+                ("nightly".to_string(), second.manifest_hash),
+                ("stable".to_string(), first.manifest_hash),
+            ])
+        );
+
+        let install = |channel: &str| {
+            install_artifact_version(
+                &name,
+                &VersionSelector::Channel(channel.to_string()),
+                &store,
+            )
+        };
+        let installed =
+            || fs::read_to_string(store.path.join("artifacts/channelled/build")).unwrap();
+        install("stable").unwrap();
+        assert_eq!(installed(), "first");
+        install("nightly").unwrap();
+        assert_eq!(installed(), "second");
+        assert!(install("beta").is_err());
     }
 
     #[test]
-    #[cfg(feature = "decoding")]
-    fn test_create_temp_file_overflow_test() {
-        let dir = temp_dir().join("lcas_temp_file_overflow_test");
-        let _ = std::fs::remove_dir_all(&dir);
-        std::fs::create_dir_all(&dir).unwrap();
+    #[cfg(all(feature = "encoding", feature = "decoding"))]
+    fn test_concurrent_builds() {
+        use std::path::PathBuf;
 
-        for _i in 0..1000 {
-            let file_name = super::get_temp_file(None, &dir);
-            File::create_new(&file_name).unwrap();
-            assert!(file_name.exists());
+        use crate::{build, list_versions};
+
+        let store = create_test_store("concurrent_builds");
+        let repo = PathBuf::from(&store.repos.first().unwrap());
+        let input_dir = temp_dir().join("lcas_artifact_test_concurrent_builds");
+        let _ = fs::remove_dir_all(&input_dir);
+
+        // Every builder shares some chunks with the others, and adds to the same artifact
+        std::thread::scope(|scope| {
+            for i in 0..8 {
+                let (repo, input_dir) = (&repo, input_dir.join(i.to_string()));
+                scope.spawn(move || {
+                    fs::create_dir_all(&input_dir).unwrap();
+                    fs::write(input_dir.join("shared"), b"Shared").unwrap();
+                    fs::write(input_dir.join("own"), format!("Builder {i}")).unwrap();
+                    build(&input_dir, repo, &format!("builder{i}")).unwrap();
+                    build(&input_dir, repo, "shared").unwrap();
+                });
+            }
+        });
+
+        for i in 0..8 {
+            assert_eq!(
+                list_versions(&format!("builder{i}"), &store).unwrap().len(),
+                1
+            );
         }
+        assert_eq!(list_versions("shared", &store).unwrap().len(), 8);
+        // Nothing was left half written
+        let leftovers = ["chunks", "manifests", ""]
+            .iter()
+            .flat_map(|dir| fs::read_dir(repo.join(dir)).unwrap().flatten())
+            .filter(|f| f.file_name().to_string_lossy().contains(".tmp_"))
+            .count();
+        assert_eq!(leftovers, 0);
     }
 
     #[test]
-    #[cfg(feature = "decoding")]
-    fn test_get_temp_file_returns_unique_names() {
-        let dir = temp_dir().join("lcas_temp_file_unique_test");
-        let _ = std::fs::create_dir_all(&dir);
-        let file1 = super::get_temp_file(None, &dir);
-        std::fs::File::create(dir.join(&file1)).unwrap();
-        let file2 = super::get_temp_file(None, &dir);
-        assert_ne!(file1, file2);
-        let _ = remove_dir_all(&dir);
+    #[cfg(all(feature = "encoding", feature = "decoding"))]
+    fn test_remove_and_rename_artifacts() {
+        use std::collections::BTreeSet;
+        use std::path::PathBuf;
+
+        use crate::{
+            BuildOptions, IndexError, build, build_with_options, install_artifact, list_versions,
+            remove_artifact, remove_artifact_with_report, rename_artifact,
+        };
+
+        let store = create_test_store("remove_rename");
+        let repo = PathBuf::from(&store.repos.first().unwrap());
+        let input_dir = temp_dir().join("lcas_artifact_test_remove_rename");
+        let hash = |data: &[u8]| crate::hash::hash(crate::HashAlgorithm::Xxh3, data);
+
+        let _ = fs::remove_dir_all(&input_dir);
+        fs::create_dir_all(input_dir.join("dir")).unwrap();
+        fs::write(input_dir.join("dir/shared"), b"Shared").unwrap();
+        fs::write(input_dir.join("dir/own"), b"First").unwrap();
+        let trees = BuildOptions {
+            trees: true,
+            ..BuildOptions::default()
+        };
+        let first = build_with_options(&input_dir, &repo, "first", &trees).unwrap();
+        let tree_count = fs::read_dir(repo.join("trees")).unwrap().count();
+        // The same manifest is also a build of `kept`, so stays referenced
+        build_with_options(&input_dir, &repo, "kept", &trees).unwrap();
+        fs::write(input_dir.join("dir/own"), b"Second").unwrap();
+        let second = build(&input_dir, &repo, "first").unwrap();
+        fs::remove_file(input_dir.join("dir/own")).unwrap();
+        let third = build(&input_dir, &repo, "renamed").unwrap();
+
+        let unreferenced = remove_artifact_with_report(&repo, "first").unwrap();
+        assert_eq!(unreferenced.manifests, BTreeSet::from([second.clone()]));
+        assert!(unreferenced.trees.is_empty());
+        assert_eq!(unreferenced.chunks, BTreeSet::from([hash(b"Second")]));
+        assert!(list_versions("first", &store).unwrap().is_empty());
+        assert_eq!(
+            list_versions("kept", &store).unwrap()[0].manifest_hash,
+            first.manifest_hash
+        );
+
+        rename_artifact(&repo, "renamed", "moved").unwrap();
+        install_artifact(&"moved".to_string(), &store).unwrap();
+        assert_eq!(
+            fs::read(store.path.join("artifacts/moved/dir/shared")).unwrap(),
+            b"Shared"
+        );
+        let error = rename_artifact(&repo, "moved", "kept").unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<IndexError>(),
+            Some(&IndexError::ArtifactExists {
+                name: "kept".to_string()
+            })
+        );
+
+        // Only `moved` still uses the shared chunk, and nothing uses the trees
+        let unreferenced = remove_artifact_with_report(&repo, "kept").unwrap();
+        assert_eq!(
+            unreferenced.manifests,
+            BTreeSet::from([first.manifest_hash])
+        );
+        assert_eq!(unreferenced.trees.len(), tree_count);
+        assert_eq!(unreferenced.chunks, BTreeSet::from([hash(b"First")]));
+        assert_eq!(
+            list_versions("moved", &store).unwrap()[0].manifest_hash,
+            third
+        );
+
+        remove_artifact(&repo, "moved").unwrap();
+        assert!(remove_artifact(&repo, "moved").is_err());
+    }
+
+    #[test]
+    #[cfg(all(feature = "encoding", feature = "decoding"))]
+    fn test_install_version_from_later_repo() {
+        use std::path::PathBuf;
+
+        use crate::{BuildOptions, VersionSelector, build_with_options, install_artifact_version};
+
+        let mut store = create_test_store("version_from_later_repo");
+        let first_repo = PathBuf::from(&store.repos.first().unwrap());
+        let second_repo = temp_dir().join("lcas_testing_repo_version_from_later_repo_second");
+        let _ = remove_dir_all(&second_repo);
+        create_repo(&second_repo).unwrap();
+        store.repos.push(second_repo.to_string_lossy().to_string());
+        let input_dir = temp_dir().join("lcas_artifact_test_version_from_later_repo");
+        let name = "versioned".to_string();
+        let options = |version: &str, channels: &[&str]| BuildOptions {
+            version: Some(version.to_string()),
+            channels: channels.iter().map(ToString::to_string).collect(),
+            ..BuildOptions::default()
+        };
+
+        let _ = fs::remove_dir_all(&input_dir);
+        fs::create_dir_all(&input_dir).unwrap();
+        fs::write(input_dir.join("version"), "1.0").unwrap();
+        build_with_options(&input_dir, &first_repo, &name, &options("1.0", &["stable"])).unwrap();
+        fs::write(input_dir.join("version"), "2.0").unwrap();
+        build_with_options(&input_dir, &second_repo, &name, &options("2.0", &["beta"])).unwrap();
+
+        // Only the second repo has these, even though the first has other builds of the same artifact
+        let install =
+            |selector: VersionSelector| install_artifact_version(&name, &selector, &store);
+        let installed =
+            || fs::read_to_string(store.path.join("artifacts/versioned/version")).unwrap();
+        install(VersionSelector::Version("2.0".to_string())).unwrap();
+        assert_eq!(installed(), "2.0");
+        install(VersionSelector::Channel("stable".to_string())).unwrap();
+        assert_eq!(installed(), "1.0");
+        install(VersionSelector::Channel("beta".to_string())).unwrap();
+        assert_eq!(installed(), "2.0");
+        // The first repo still comes first for anything both have
+        install(VersionSelector::Latest).unwrap();
+        assert_eq!(installed(), "1.0");
+        assert!(install(VersionSelector::Version("3.0".to_string())).is_err());
+    }
+
+    #[test]
+    #[cfg(all(feature = "encoding", feature = "decoding"))]
+    fn test_list_artifacts_across_repos() {
+        use std::path::PathBuf;
+
+        use crate::{BuildOptions, Metadata, build, build_with_options, install_artifact};
+
+        let mut store = create_test_store("list_artifacts");
+        let first_repo = PathBuf::from(&store.repos.first().unwrap());
+        let second_repo = temp_dir().join("lcas_testing_repo_list_artifacts_second");
+        let _ = remove_dir_all(&second_repo);
+        create_repo(&second_repo).unwrap();
+        store.repos.push(second_repo.to_string_lossy().to_string());
+        let input_dir = temp_dir().join("lcas_artifact_test_list_artifacts");
+
+        let _ = fs::remove_dir_all(&input_dir);
+        fs::create_dir_all(&input_dir).unwrap();
+        fs::write(input_dir.join("file"), b"Shared").unwrap();
+        let shared = build(&input_dir, &first_repo, "shared").unwrap();
+        assert_eq!(build(&input_dir, &second_repo, "shared").unwrap(), shared);
+        let options = BuildOptions {
+            metadata: Metadata {
+                commit: Some("abc123".to_string()),
+                ..Metadata::default()
+            },
+            version: Some("1.0".to_string()),
+            ..BuildOptions::default()
+        };
+        let described = build_with_options(&input_dir, &first_repo, "a", &options).unwrap();
+        fs::write(input_dir.join("file"), b"Only in the second repo").unwrap();
+        let other = build(&input_dir, &second_repo, "a").unwrap();
+        let only_second = build(&input_dir, &second_repo, "b").unwrap();
+
+        let listed = store.list_artifacts().unwrap();
+        assert_eq!(
+            listed
+                .iter()
+                .map(|artifact| (
+                    artifact.name.as_str(),
+                    artifact.manifest_hash.as_str(),
+                    PathBuf::from(&artifact.repo)
+                ))
+                .collect::<Vec<_>>(),
+            vec![
+                ("a", described.manifest_hash.as_str(), first_repo.clone()),
+                ("a", other.as_str(), second_repo.clone()),
+                ("b", only_second.as_str(), second_repo.clone()),
+                ("shared", shared.as_str(), first_repo.clone()),
+            ]
+        );
+        assert_eq!(listed[0].metadata, options.metadata);
+        assert_eq!(listed[0].version.as_deref(), Some("1.0"));
+        assert!(listed[0].timestamp.is_some());
+
+        // Artifacts only in a later repo are still found and installed
+        let found = store.find_artifact("b").unwrap().unwrap();
+        assert_eq!(found, listed[2]);
+        assert_eq!(store.find_artifact("missing").unwrap(), None);
+        install_artifact(&"b".to_string(), &store).unwrap();
+        assert_eq!(
+            fs::read(store.path.join("artifacts/b/file")).unwrap(),
+            b"Only in the second repo"
+        );
+
+        // The index is read again every time, so new builds show up straight away
+        build(&input_dir, &first_repo, "c").unwrap();
+        assert!(store.find_artifact("c").unwrap().is_some());
+        assert_eq!(store.list_artifacts().unwrap().len(), 5);
     }
 
     #[test]
     #[cfg(feature = "decoding")]
-    fn test_make_chunk_executable_sets_permissions() {
-        let dir = temp_dir().join("lcas_executable_test");
-        let _ = fs::create_dir_all(dir.join("chunks"));
-        let chunk_hash = "testchunk".to_string();
-        let chunk_path = dir.join("chunks").join(&chunk_hash);
-        File::create(&chunk_path).unwrap();
+    fn test_repo_url() {
+        assert_eq!(
+            crate::repo_url("https://example.com/repo", "manifests/1"),
+            "https://example.com/repo/manifests/1"
+        );
+        assert_eq!(
+            crate::repo_url("https://example.com/repo/", "artifacts"),
+            "https://example.com/repo/artifacts"
+        );
+    }
+
+    #[test]
+    #[cfg(all(feature = "encoding", feature = "decoding"))]
+    fn test_shared_content_keeps_separate_modes() {
+        use std::path::PathBuf;
 
-        super::make_chunk_executable(&chunk_hash, &dir).unwrap();
+        use crate::{build, install_artifact};
 
-        let perms = fs::metadata(&chunk_path).unwrap().permissions();
-        assert_eq!(perms.mode() & 0o111, 0o111);
+        let store = create_test_store("shared_content_modes");
+        let repo = PathBuf::from(&store.repos.first().unwrap());
+        let input_dir = temp_dir().join("lcas_artifact_test_shared_content_modes");
 
-        let _ = remove_dir_all(&dir);
+        let _ = fs::remove_dir_all(&input_dir);
+        fs::create_dir_all(&input_dir).unwrap();
+        fs::write(input_dir.join("tool"), b"#!/bin/sh").unwrap();
+        fs::write(input_dir.join("script"), b"#!/bin/sh").unwrap();
+        fs::set_permissions(input_dir.join("tool"), fs::Permissions::from_mode(0o755)).unwrap();
+        fs::set_permissions(input_dir.join("script"), fs::Permissions::from_mode(0o644)).unwrap();
+        build(&input_dir, &repo, "first").unwrap();
+
+        // The same content again, in another artifact with the modes swapped
+        fs::set_permissions(input_dir.join("tool"), fs::Permissions::from_mode(0o644)).unwrap();
+        fs::set_permissions(input_dir.join("script"), fs::Permissions::from_mode(0o755)).unwrap();
+        build(&input_dir, &repo, "second").unwrap();
+
+        install_artifact(&"first".to_string(), &store).unwrap();
+        install_artifact(&"second".to_string(), &store).unwrap();
+
+        let mode = |path: &str| {
+            fs::metadata(store.path.join("artifacts").join(path))
+                .unwrap()
+                .permissions()
+                .mode()
+                & 0o7777
+        };
+        assert_eq!(mode("first/tool"), 0o755);
+        assert_eq!(mode("first/script"), 0o644);
+        assert_eq!(mode("second/tool"), 0o644);
+        assert_eq!(mode("second/script"), 0o755);
     }
 
     #[test]
     #[cfg(all(feature = "encoding", feature = "decoding"))]
-    fn test_create_and_load_artifact() {
+    fn test_install_rejects_tampered_manifest() {
         use std::path::PathBuf;
 
         use crate::{build, install_artifact};
 
-        let store = create_test_store("artifact");
-        let input_dir = temp_dir().join("lcas_artifact_test");
+        let store = create_test_store("tampered_manifest");
+        let repo = PathBuf::from(&store.repos.first().unwrap());
+        let input_dir = temp_dir().join("lcas_artifact_test_tampered_manifest");
+
+        let _ = fs::remove_dir_all(&input_dir);
+        fs::create_dir_all(&input_dir).unwrap();
+        fs::write(input_dir.join("tool"), b"#!/bin/sh").unwrap();
+        fs::set_permissions(input_dir.join("tool"), fs::Permissions::from_mode(0o644)).unwrap();
+
+        let manifest_hash = build(&input_dir, &repo, "tampered").unwrap();
+        let manifest_path = repo.join("manifests").join(manifest_hash);
+        let mut manifest =
+            crate::manifest::Manifest::decode(&fs::read(&manifest_path).unwrap()).unwrap();
+        manifest.entries[0].attributes.mode = 0o4755;
+        fs::write(&manifest_path, manifest.encode()).unwrap();
+
+        let error = install_artifact(&"tampered".to_string(), &store).unwrap_err();
+        assert!(error.to_string().contains("doesn't match its hash"));
+    }
+
+    #[test]
+    #[cfg(all(feature = "encoding", feature = "decoding"))]
+    fn test_install_rejects_corrupt_chunk() {
+        use std::path::PathBuf;
+
+        use crate::{build, install_artifact};
+
+        let store = create_test_store("corrupt_chunk");
+        let repo = PathBuf::from(&store.repos.first().unwrap());
+        let input_dir = temp_dir().join("lcas_artifact_test_corrupt_chunk");
 
         let _ = fs::remove_dir_all(&input_dir);
         fs::create_dir_all(&input_dir).unwrap();
         fs::write(input_dir.join("file1.txt"), b"Hello, world!").unwrap();
-        fs::write(input_dir.join("file2.txt"), b"Another file.").unwrap();
+        build(&input_dir, &repo, "corrupt").unwrap();
 
-        // Create a nested directory and files inside it
-        let nested_dir = input_dir.join("nested");
-        fs::create_dir(&nested_dir).unwrap();
-        fs::write(nested_dir.join("nested1.txt"), b"Nested file 1.").unwrap();
-        fs::write(nested_dir.join("nested2.txt"), b"Nested file 2.").unwrap();
+        // Not a zstd frame at all, which has to fail the install rather than panic
+        let chunk_hash = crate::hash::hash(crate::HashAlgorithm::Xxh3, b"Hello, world!");
+        fs::write(repo.join("chunks").join(&chunk_hash), b"not zstd").unwrap();
 
-        // Create a deeper nested directory
-        let deeper_nested_dir = nested_dir.join("deeper");
-        fs::create_dir(&deeper_nested_dir).unwrap();
-        fs::write(deeper_nested_dir.join("deepfile.txt"), b"Deep nested file.").unwrap();
+        let error = install_artifact(&"corrupt".to_string(), &store).unwrap_err();
+        assert!(format!("{error:#}").contains(&chunk_hash));
+    }
 
-        build(
-            &input_dir,
-            &PathBuf::from(&store.repos.first().unwrap()),
-            "test_artifact",
-        )
-        .unwrap();
+    #[test]
+    #[cfg(all(feature = "encoding", feature = "decoding"))]
+    fn test_install_rejects_path_traversal() {
+        use std::path::PathBuf;
 
-        install_artifact(&"test_artifact".to_string(), &store).unwrap();
+        use crate::{build, install_artifact};
+
+        let store = create_test_store("path_traversal");
+        let repo = PathBuf::from(&store.repos.first().unwrap());
+        let input_dir = temp_dir().join("lcas_artifact_test_path_traversal");
+        let escaped = temp_dir().join("lcas_path_traversal_escaped");
+        let _ = fs::remove_file(&escaped);
+
+        let _ = fs::remove_dir_all(&input_dir);
+        fs::create_dir_all(&input_dir).unwrap();
+        fs::write(input_dir.join("file1.txt"), b"Hello, world!").unwrap();
+        build(&input_dir, &repo, "path_traversal").unwrap();
+
+        // Point the artifact at hand-written format 1 manifests that try to escape the Store, named by their real
+        // hashes, so they're only rejected for what they contain
+        let chunk_hash = crate::hash::hash(crate::HashAlgorithm::Xxh3, b"Hello, world!");
+        let install_files = |files: &[(&str, bool)]| {
+            let files: Vec<_> = files
+                .iter()
+                .map(|(path, executable)| (*path, chunk_hash.as_str(), *executable))
+                .collect();
+            let manifest_hash = crate::hash::hash_legacy_manifest(files.iter().copied());
+            fs::write(
+                repo.join("manifests").join(&manifest_hash),
+                serde_json::json!({"files": files, "format": 1}).to_string(),
+            )
+            .unwrap();
+            fs::write(
+                repo.join("artifacts"),
+                format!("path_traversal:{manifest_hash}\n"),
+            )
+            .unwrap();
+
+            install_artifact(&"path_traversal".to_string(), &store)
+        };
+
+        // A well-formed one installs, so the rest only fail for their paths
+        install_files(&[("/file1.txt", false)]).unwrap();
+        for path in [
+            "/../../../../../../lcas_path_traversal_escaped",
+            "/file1.txt\0",
+            "/nested/../../../escape",
+        ] {
+            assert!(install_files(&[(path, false)]).is_err());
+        }
+
+        // Listing the same file twice is rejected too
+        assert!(install_files(&[("/a", false), ("/a", true)]).is_err());
+
+        assert!(!escaped.exists());
     }
 
     #[test]
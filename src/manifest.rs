@@ -0,0 +1,1060 @@
+#![warn(clippy::pedantic)]
+
+use anyhow::{Result, bail};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// The format written by `build`.
+/// Format 1 manifests only listed regular files, as JSON. From format 2, manifests are written in their canonical
+/// binary encoding, compressed with zstd.
+pub const FORMAT: u8 = 2;
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub struct Manifest {
+    pub entries: Vec<Entry>,
+    /// Files that were split into several chunks, as (file hash -> chunk hashes).
+    /// Any file not listed here is stored as a single chunk named by its own hash.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub chunks: BTreeMap<String, Vec<String>>,
+    /// Uncompressed size of every file, by file hash. Empty in format 1.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub sizes: BTreeMap<String, u64>,
+    /// Compressed size of every chunk in the repo, by chunk hash, including files stored as a single chunk.
+    /// Empty in format 1.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub chunk_sizes: BTreeMap<String, u64>,
+    /// Hash of the root [`Tree`](crate::tree::Tree), for manifests built with trees.
+    /// `entries` and `chunks` are then empty, as they're read from the trees instead.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub root: Option<String>,
+    #[serde(default, skip_serializing_if = "Metadata::is_empty")]
+    pub metadata: Metadata,
+    pub format: u8,
+}
+
+/// Where an artifact came from, recorded when it's built.
+///
+/// It's part of the manifest, so it's covered by the manifest's hash like everything else.
+/// Nothing here is captured automatically, as it would stop the same tree from producing the same manifest.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Metadata {
+    /// The commit the artifact was built from.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub commit: Option<String>,
+    /// The CI job (or any other build) that produced the artifact.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub job: Option<String>,
+    /// When the artifact was built, in seconds since the epoch.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub build_time: Option<i64>,
+    /// The toolchain the artifact was built with, such as `rustc 1.90.0`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub toolchain: Option<String>,
+    /// Anything else, by name.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub extra: BTreeMap<String, String>,
+}
+
+impl Metadata {
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self == &Self::default()
+    }
+}
+
+/// A single path in an artifact, relative to its root.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Entry {
+    pub path: String,
+    #[serde(flatten)]
+    pub kind: EntryKind,
+    #[serde(flatten)]
+    pub attributes: Attributes,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum EntryKind {
+    File {
+        hash: String,
+    },
+    /// Recreated as-is, the target is never followed or rewritten.
+    Symlink {
+        target: String,
+    },
+    /// Parent directories that aren't listed are created as `0o755`.
+    Directory,
+    /// Another path to the same file as `target`, which is a `File` entry with the same attributes.
+    Hardlink {
+        target: String,
+    },
+    /// Can only be installed as root.
+    CharDevice {
+        major: u32,
+        minor: u32,
+    },
+    /// Can only be installed as root.
+    BlockDevice {
+        major: u32,
+        minor: u32,
+    },
+    Fifo,
+    Socket,
+    /// A subdirectory, stored as its own tree. Only found in trees, never in a manifest's entries.
+    Tree {
+        hash: String,
+    },
+}
+
+impl EntryKind {
+    // Device nodes need `CAP_MKNOD` to be created
+    #[cfg(feature = "decoding")]
+    pub fn is_device(&self) -> bool {
+        matches!(self, Self::CharDevice { .. } | Self::BlockDevice { .. })
+    }
+}
+
+/// Extended attribute names mapped to their raw values.
+pub type Xattrs = BTreeMap<String, Vec<u8>>;
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Attributes {
+    /// Permission bits, including setuid, setgid and sticky.
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    /// Modification time in whole seconds since the epoch, as sub-second precision varies between filesystems.
+    pub mtime: i64,
+    /// Only captured from the namespaces a build allows, and never for symlinks.
+    #[serde(
+        default,
+        skip_serializing_if = "BTreeMap::is_empty",
+        with = "hex_values"
+    )]
+    pub xattrs: Xattrs,
+}
+
+// Xattr values are arbitrary bytes, so they're stored as hex
+mod hex_values {
+    use super::Xattrs;
+    use serde::{Deserialize, Deserializer, Serializer, de::Error};
+    use std::collections::BTreeMap;
+
+    pub fn serialize<S: Serializer>(xattrs: &Xattrs, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_map(
+            xattrs
+                .iter()
+                .map(|(name, value)| (name, hex::encode(value))),
+        )
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Xattrs, D::Error> {
+        BTreeMap::<String, String>::deserialize(deserializer)?
+            .into_iter()
+            .map(|(name, value)| Ok((name, hex::decode(value).map_err(D::Error::custom)?)))
+            .collect()
+    }
+}
+
+impl Attributes {
+    // Setuid and setgid are dropped when ownership can't be applied, as they would grant the installing user's
+    // privileges rather than the owner's.
+    #[cfg(feature = "decoding")]
+    pub fn effective_mode(&self, privileged: bool) -> u32 {
+        if privileged {
+            self.mode
+        } else {
+            self.mode & !0o6000
+        }
+    }
+
+    // Only `user.` xattrs can be set without privileges, so the rest are skipped rather than failing the install
+    #[cfg(feature = "decoding")]
+    pub fn effective_xattrs(&self, privileged: bool) -> impl Iterator<Item = (&String, &Vec<u8>)> {
+        self.xattrs
+            .iter()
+            .filter(move |(name, _)| privileged || name.starts_with("user."))
+    }
+}
+
+// Upgrades a JSON manifest from one format to the next
+#[cfg(any(feature = "decoding", feature = "encoding"))]
+type Migration = fn(serde_json::Value) -> Result<serde_json::Value>;
+
+// The migration from every known format to the one after it, starting at format 1.
+// A manifest of any known format is upgraded by every migration from its own onwards, in order.
+// Each one reads its format strictly, and builds the next only from the fields that format defined. Anything else
+// is rejected, as a hash of an older format may not cover it.
+#[cfg(any(feature = "decoding", feature = "encoding"))]
+const MIGRATIONS: [Migration; FORMAT as usize - 1] = [migrate_v1];
+
+// A format 1 manifest, which is exactly what its hash covered. Any other key is rejected rather than carried over, as
+// nothing would check it.
+#[cfg(any(feature = "decoding", feature = "encoding"))]
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ManifestV1 {
+    files: Vec<(String, String, bool)>,
+    // Already read to pick the migrations
+    #[serde(rename = "format")]
+    _format: u8,
+}
+
+// Format 1 could only hold regular files, as (path, hash, executable) tuples, and knew nothing of their attributes
+#[cfg(any(feature = "decoding", feature = "encoding"))]
+fn migrate_v1(manifest: serde_json::Value) -> Result<serde_json::Value> {
+    let manifest: ManifestV1 = serde_json::from_value(manifest)?;
+
+    let entries: Vec<_> = manifest
+        .files
+        .into_iter()
+        .map(|(path, hash, executable)| {
+            let mode = if executable { 0o755 } else { 0o644 };
+            serde_json::json!({
+                "path": path, "type": "file", "hash": hash, "mode": mode, "uid": 0, "gid": 0, "mtime": 0
+            })
+        })
+        .collect();
+
+    Ok(serde_json::json!({"entries": entries, "format": 2}))
+}
+
+#[cfg(any(feature = "decoding", feature = "encoding"))]
+impl Manifest {
+    // A manifest in the current format with only these entries, and nothing split, sized or described yet
+    pub fn new(entries: Vec<Entry>) -> Self {
+        Self {
+            entries,
+            chunks: BTreeMap::new(),
+            sizes: BTreeMap::new(),
+            chunk_sizes: BTreeMap::new(),
+            root: None,
+            metadata: Metadata::default(),
+            format: FORMAT,
+        }
+    }
+
+    // Encodes the whole manifest, including its format, so that any two different manifests have different bytes.
+    // This is what manifests are hashed over.
+    pub fn canonical_bytes(&self) -> Vec<u8> {
+        let mut encoder = crate::binary::Encoder::default();
+        encoder.u8(self.format);
+        encode_entries(&mut encoder, &self.entries);
+        encode_chunks(&mut encoder, &self.chunks);
+        encode_sizes(&mut encoder, &self.sizes);
+        encode_sizes(&mut encoder, &self.chunk_sizes);
+        encoder.optional_str(self.root.as_deref());
+        encode_metadata(&mut encoder, &self.metadata);
+
+        encoder.finish()
+    }
+
+    // The bytes written to a repo
+    #[cfg(feature = "encoding")]
+    pub fn encode(&self) -> Vec<u8> {
+        crate::compression::compress_file(&self.canonical_bytes(), 3)
+    }
+
+    // Reads back the binary encoding written by `canonical_bytes`.
+    // Returns the format it was written in, alongside the manifest.
+    fn from_canonical_bytes(bytes: &[u8]) -> Result<(Self, u64)> {
+        let mut decoder = crate::binary::Decoder::new(bytes);
+
+        // Format 1 was only ever written as JSON
+        let format = decoder.u8()?;
+        if format != FORMAT {
+            return Err(crate::InstallError::UnsupportedManifestFormat {
+                format: format.into(),
+            }
+            .into());
+        }
+
+        let manifest = Self {
+            entries: decode_entries(&mut decoder)?,
+            chunks: decode_chunks(&mut decoder)?,
+            sizes: decode_sizes(&mut decoder)?,
+            chunk_sizes: decode_sizes(&mut decoder)?,
+            root: decoder.optional_str()?,
+            metadata: decode_metadata(&mut decoder)?,
+            format,
+        };
+        decoder.finish()?;
+
+        Ok((manifest, format.into()))
+    }
+}
+
+// Shared by manifests and trees, so an entry is encoded the same way in either
+#[cfg(any(feature = "decoding", feature = "encoding"))]
+pub fn encode_entries(encoder: &mut crate::binary::Encoder, entries: &[Entry]) {
+    encoder.len(entries.len());
+    for entry in entries {
+        encoder.str(&entry.path);
+
+        match &entry.kind {
+            EntryKind::File { hash } => {
+                encoder.u8(0);
+                encoder.str(hash);
+            }
+            EntryKind::Symlink { target } => {
+                encoder.u8(1);
+                encoder.str(target);
+            }
+            EntryKind::Directory => encoder.u8(2),
+            EntryKind::Hardlink { target } => {
+                encoder.u8(3);
+                encoder.str(target);
+            }
+            EntryKind::CharDevice { major, minor } => {
+                encoder.u8(4);
+                encoder.u32(*major);
+                encoder.u32(*minor);
+            }
+            EntryKind::BlockDevice { major, minor } => {
+                encoder.u8(5);
+                encoder.u32(*major);
+                encoder.u32(*minor);
+            }
+            EntryKind::Fifo => encoder.u8(6),
+            EntryKind::Socket => encoder.u8(7),
+            EntryKind::Tree { hash } => {
+                encoder.u8(8);
+                encoder.str(hash);
+            }
+        }
+
+        let attributes = &entry.attributes;
+        encoder.u32(attributes.mode);
+        encoder.u32(attributes.uid);
+        encoder.u32(attributes.gid);
+        encoder.i64(attributes.mtime);
+        encoder.len(attributes.xattrs.len());
+        for (name, value) in &attributes.xattrs {
+            encoder.str(name);
+            encoder.bytes(value);
+        }
+    }
+}
+
+#[cfg(any(feature = "decoding", feature = "encoding"))]
+pub fn encode_chunks(encoder: &mut crate::binary::Encoder, chunks: &BTreeMap<String, Vec<String>>) {
+    encoder.len(chunks.len());
+    for (file_hash, chunk_hashes) in chunks {
+        encoder.str(file_hash);
+        encoder.len(chunk_hashes.len());
+        for chunk_hash in chunk_hashes {
+            encoder.str(chunk_hash);
+        }
+    }
+}
+
+#[cfg(any(feature = "decoding", feature = "encoding"))]
+pub fn encode_sizes(encoder: &mut crate::binary::Encoder, sizes: &BTreeMap<String, u64>) {
+    encoder.len(sizes.len());
+    for (hash, size) in sizes {
+        encoder.str(hash);
+        encoder.u64(*size);
+    }
+}
+
+#[cfg(any(feature = "decoding", feature = "encoding"))]
+fn encode_metadata(encoder: &mut crate::binary::Encoder, metadata: &Metadata) {
+    encoder.optional_str(metadata.commit.as_deref());
+    encoder.optional_str(metadata.job.as_deref());
+    encoder.optional_i64(metadata.build_time);
+    encoder.optional_str(metadata.toolchain.as_deref());
+
+    encoder.len(metadata.extra.len());
+    for (name, value) in &metadata.extra {
+        encoder.str(name);
+        encoder.str(value);
+    }
+}
+
+// Reads back entries written by `encode_entries`
+#[cfg(any(feature = "decoding", feature = "encoding"))]
+pub fn decode_entries(decoder: &mut crate::binary::Decoder) -> Result<Vec<Entry>> {
+    let mut entries = Vec::new();
+
+    for _ in 0..decoder.len()? {
+        let path = decoder.str()?;
+
+        let kind = match decoder.u8()? {
+            0 => EntryKind::File {
+                hash: decoder.str()?,
+            },
+            1 => EntryKind::Symlink {
+                target: decoder.str()?,
+            },
+            2 => EntryKind::Directory,
+            3 => EntryKind::Hardlink {
+                target: decoder.str()?,
+            },
+            4 => EntryKind::CharDevice {
+                major: decoder.u32()?,
+                minor: decoder.u32()?,
+            },
+            5 => EntryKind::BlockDevice {
+                major: decoder.u32()?,
+                minor: decoder.u32()?,
+            },
+            6 => EntryKind::Fifo,
+            7 => EntryKind::Socket,
+            8 => EntryKind::Tree {
+                hash: decoder.str()?,
+            },
+            kind => bail!("{path:?} has an unknown type {kind}"),
+        };
+
+        let mut attributes = Attributes {
+            mode: decoder.u32()?,
+            uid: decoder.u32()?,
+            gid: decoder.u32()?,
+            mtime: decoder.i64()?,
+            xattrs: Xattrs::new(),
+        };
+        for _ in 0..decoder.len()? {
+            attributes.xattrs.insert(decoder.str()?, decoder.bytes()?);
+        }
+
+        entries.push(Entry {
+            path,
+            kind,
+            attributes,
+        });
+    }
+
+    Ok(entries)
+}
+
+// Reads back chunk lists written by `encode_chunks`
+#[cfg(any(feature = "decoding", feature = "encoding"))]
+pub fn decode_chunks(
+    decoder: &mut crate::binary::Decoder,
+) -> Result<BTreeMap<String, Vec<String>>> {
+    let mut chunks = BTreeMap::new();
+
+    for _ in 0..decoder.len()? {
+        let file_hash = decoder.str()?;
+        let mut chunk_hashes = Vec::new();
+        for _ in 0..decoder.len()? {
+            chunk_hashes.push(decoder.str()?);
+        }
+        chunks.insert(file_hash, chunk_hashes);
+    }
+
+    Ok(chunks)
+}
+
+// Reads back sizes written by `encode_sizes`
+#[cfg(any(feature = "decoding", feature = "encoding"))]
+pub fn decode_sizes(decoder: &mut crate::binary::Decoder) -> Result<BTreeMap<String, u64>> {
+    let mut sizes = BTreeMap::new();
+
+    for _ in 0..decoder.len()? {
+        sizes.insert(decoder.str()?, decoder.u64()?);
+    }
+
+    Ok(sizes)
+}
+
+// Reads back metadata written by `encode_metadata`
+#[cfg(any(feature = "decoding", feature = "encoding"))]
+fn decode_metadata(decoder: &mut crate::binary::Decoder) -> Result<Metadata> {
+    let mut metadata = Metadata {
+        commit: decoder.optional_str()?,
+        job: decoder.optional_str()?,
+        build_time: decoder.optional_i64()?,
+        toolchain: decoder.optional_str()?,
+        extra: BTreeMap::new(),
+    };
+
+    for _ in 0..decoder.len()? {
+        metadata.extra.insert(decoder.str()?, decoder.str()?);
+    }
+
+    Ok(metadata)
+}
+
+#[cfg(any(feature = "decoding", feature = "encoding"))]
+impl Manifest {
+    // Parses a JSON manifest of any known format, upgrading older formats one version at a time.
+    // Only tests can skip checking the hash.
+    #[cfg(test)]
+    pub fn parse(manifest: &str) -> Result<Self> {
+        Ok(Self::parse_with_format(manifest)?.0)
+    }
+
+    // Decodes a manifest as written to a repo, without checking its hash. Only for tests, like `parse`.
+    #[cfg(all(test, feature = "encoding"))]
+    pub fn decode(manifest: &[u8]) -> Result<Self> {
+        Ok(Self::decode_with_format(manifest)?.0)
+    }
+
+    // Decodes a manifest as written to a repo, and checks it's the one named by `hash`.
+    // Format 1 manifests are checked the way they were hashed, which was only ever with xxh3, so a format 1 manifest
+    // under any other name is rejected rather than accepted unchecked.
+    pub fn decode_verified(manifest: &[u8], hash: &str) -> Result<Self> {
+        use crate::hash::{HashAlgorithm, hash_legacy_manifest, hash_manifest};
+
+        let (manifest, format) = Self::decode_with_format(manifest)?;
+
+        let expected = match (format, HashAlgorithm::of(hash)?) {
+            (1, HashAlgorithm::Xxh3) => hash_legacy_manifest(manifest.legacy_files()),
+            (1, _) => bail!(
+                "Format 1 manifests can't be named by {hash:?}, as they were only hashed with xxh3"
+            ),
+            (_, algorithm) => hash_manifest(algorithm, &manifest),
+        };
+        if expected != hash {
+            bail!("Manifest doesn't match its hash {hash:?}");
+        }
+
+        Ok(manifest)
+    }
+
+    // The (path, hash, executable) tuples a format 1 manifest listed, recovered from the entries `migrate_v1` made
+    fn legacy_files(&self) -> impl Iterator<Item = (&str, &str, bool)> {
+        self.entries.iter().filter_map(|entry| match &entry.kind {
+            EntryKind::File { hash } => Some((
+                entry.path.as_str(),
+                hash.as_str(),
+                entry.attributes.mode == 0o755,
+            )),
+            _ => None,
+        })
+    }
+
+    // Binary manifests are compressed, so start with zstd's magic number, which JSON never can.
+    // Either way, returns the format the manifest was written in, alongside the upgraded manifest.
+    fn decode_with_format(manifest: &[u8]) -> Result<(Self, u64)> {
+        use crate::compression::{MAGIC, try_decompress};
+
+        if manifest.starts_with(&MAGIC) {
+            return Self::from_canonical_bytes(&try_decompress(manifest)?);
+        }
+
+        Self::parse_with_format(std::str::from_utf8(manifest)?)
+    }
+
+    fn parse_with_format(manifest: &str) -> Result<(Self, u64)> {
+        let mut manifest: serde_json::Value = serde_json::from_str(manifest)?;
+
+        let Some(format) = manifest.get("format").and_then(serde_json::Value::as_u64) else {
+            bail!("Manifest is missing its format");
+        };
+
+        if format == 0 || format > u64::from(FORMAT) {
+            return Err(crate::InstallError::UnsupportedManifestFormat { format }.into());
+        }
+        for migrate in &MIGRATIONS[usize::try_from(format)? - 1..] {
+            manifest = migrate(manifest)?;
+        }
+
+        Ok((serde_json::from_value(manifest)?, format))
+    }
+}
+
+#[cfg(feature = "decoding")]
+impl Manifest {
+    // Rejects any manifest with a path that would escape the artifact, or a hash that isn't a hash
+    pub fn validate(&self) -> Result<()> {
+        use crate::hash::HashAlgorithm;
+        use crate::paths;
+
+        paths::validate(self.entries.iter().map(|entry| entry.path.as_str()))?;
+
+        let files: BTreeMap<&str, &Attributes> = self
+            .entries
+            .iter()
+            .filter(|entry| matches!(entry.kind, EntryKind::File { .. }))
+            .map(|entry| (entry.path.as_str(), &entry.attributes))
+            .collect();
+
+        for entry in &self.entries {
+            if entry.attributes.mode > 0o7777 {
+                bail!(
+                    "{:?} has an invalid mode {:o}",
+                    entry.path,
+                    entry.attributes.mode
+                );
+            }
+            // -1 means "unchanged" to chown, rather than a real owner
+            if entry.attributes.uid == u32::MAX || entry.attributes.gid == u32::MAX {
+                bail!("{:?} has an invalid owner", entry.path);
+            }
+            for name in entry.attributes.xattrs.keys() {
+                if !name.contains('.') || name.contains('\0') {
+                    bail!("{:?} has an invalid xattr {name:?}", entry.path);
+                }
+            }
+            if !entry.attributes.xattrs.is_empty()
+                && !matches!(entry.kind, EntryKind::File { .. } | EntryKind::Directory)
+            {
+                bail!(
+                    "{:?} can't have xattrs, as it isn't a file or directory",
+                    entry.path
+                );
+            }
+
+            match &entry.kind {
+                EntryKind::File { hash } => {
+                    HashAlgorithm::of(hash)?;
+                }
+                EntryKind::Symlink { target } => {
+                    if target.is_empty() || target.contains('\0') {
+                        bail!("Symlink {:?} has an invalid target", entry.path);
+                    }
+                }
+                EntryKind::Directory
+                | EntryKind::CharDevice { .. }
+                | EntryKind::BlockDevice { .. }
+                | EntryKind::Fifo
+                | EntryKind::Socket => {}
+                // Trees are read back into entries before they're validated
+                EntryKind::Tree { .. } => {
+                    bail!(
+                        "{:?} is a tree, which can't be in a manifest's entries",
+                        entry.path
+                    );
+                }
+                EntryKind::Hardlink { target } => {
+                    if files.get(target.as_str()) != Some(&&entry.attributes) {
+                        bail!(
+                            "Hard link {:?} doesn't match a file at {target:?}",
+                            entry.path
+                        );
+                    }
+                }
+            }
+        }
+
+        for (file_hash, chunk_hashes) in &self.chunks {
+            HashAlgorithm::of(file_hash)?;
+            for chunk_hash in chunk_hashes {
+                HashAlgorithm::of(chunk_hash)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+#[cfg(feature = "decoding")]
+mod tests {
+    use super::*;
+
+    fn attributes(mode: u32) -> Attributes {
+        Attributes {
+            mode,
+            uid: 0,
+            gid: 0,
+            mtime: 0,
+            xattrs: Xattrs::new(),
+        }
+    }
+
+    #[test]
+    fn parse_format_1() {
+        let manifest = Manifest::parse(r#"{"files": [["/a", "123", true]], "format": 1}"#).unwrap();
+
+        assert_eq!(
+            manifest.entries,
+            vec![Entry {
+                path: "/a".to_string(),
+                kind: EntryKind::File {
+                    hash: "123".to_string(),
+                },
+                attributes: attributes(0o755),
+            }]
+        );
+        assert_eq!(manifest.format, FORMAT);
+    }
+
+    #[test]
+    fn parse_round_trip() {
+        let manifest = Manifest::new(vec![
+            Entry {
+                path: "/usr/bin/su".to_string(),
+                kind: EntryKind::File {
+                    hash: "123".to_string(),
+                },
+                attributes: Attributes {
+                    mode: 0o4755,
+                    uid: 0,
+                    gid: 0,
+                    mtime: 1_700_000_000,
+                    xattrs: Xattrs::from([(
+                        "security.capability".to_string(),
+                        vec![0, 0, 0, 2, 0, 0x20],
+                    )]),
+                },
+            },
+            Entry {
+                path: "/lib".to_string(),
+                kind: EntryKind::Symlink {
+                    target: "usr/lib".to_string(),
+                },
+                attributes: attributes(0o777),
+            },
+            Entry {
+                path: "/var/empty".to_string(),
+                kind: EntryKind::Directory,
+                attributes: Attributes {
+                    mode: 0o700,
+                    uid: 1000,
+                    gid: 1000,
+                    mtime: 0,
+                    xattrs: Xattrs::new(),
+                },
+            },
+        ]);
+
+        let parsed = Manifest::parse(&serde_json::to_string(&manifest).unwrap()).unwrap();
+        assert_eq!(parsed, manifest);
+    }
+
+    #[test]
+    fn parse_unknown_format() {
+        assert!(Manifest::parse(r#"{"entries": [], "format": 200}"#).is_err());
+        assert!(Manifest::parse(r#"{"entries": [], "format": 0}"#).is_err());
+        assert!(Manifest::parse(r#"{"entries": []}"#).is_err());
+
+        let error = Manifest::parse(r#"{"entries": [], "format": 200}"#).unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<crate::InstallError>(),
+            Some(&crate::InstallError::UnsupportedManifestFormat { format: 200 })
+        );
+        assert_eq!(error.to_string(), "Unsupported manifest format 200");
+    }
+
+    #[test]
+    fn every_format_upgrades_to_current() {
+        for format in 1..=FORMAT {
+            let manifest = if format == 1 {
+                serde_json::json!({"files": [], "format": format})
+            } else {
+                serde_json::json!({"entries": [], "format": format})
+            };
+
+            let (manifest, original) = Manifest::parse_with_format(&manifest.to_string()).unwrap();
+            assert_eq!(original, u64::from(format));
+            assert_eq!(manifest.format, FORMAT);
+        }
+    }
+
+    #[test]
+    fn every_migration_rejects_unknown_fields() {
+        // The smallest valid manifest of each format a migration reads, so every new migration needs one here
+        let minimal: [serde_json::Value; MIGRATIONS.len()] =
+            [serde_json::json!({"files": [], "format": 1})];
+
+        for (migrate, manifest) in MIGRATIONS.iter().zip(minimal) {
+            assert!(migrate(manifest.clone()).is_ok());
+            let mut unknown = manifest;
+            unknown["unknown"] = "555".into();
+            assert!(migrate(unknown).is_err());
+        }
+    }
+
+    #[test]
+    fn migrate_v1_moves_files_to_entries() {
+        let manifest = migrate_v1(serde_json::json!({
+            "files": [["/a", "123", true], ["/b", "456", false]],
+            "format": 1
+        }));
+
+        assert_eq!(
+            manifest.unwrap(),
+            serde_json::json!({"entries": [
+                {"path": "/a", "type": "file", "hash": "123", "mode": 0o755, "uid": 0, "gid": 0, "mtime": 0},
+                {"path": "/b", "type": "file", "hash": "456", "mode": 0o644, "uid": 0, "gid": 0, "mtime": 0}
+            ], "format": 2})
+        );
+        assert!(migrate_v1(serde_json::json!({"format": 1})).is_err());
+        assert!(migrate_v1(serde_json::json!({"files": [], "root": "555", "format": 1})).is_err());
+    }
+
+    #[test]
+    fn xattrs_are_stored_as_hex() {
+        let mut entry = Entry {
+            path: "/ping".to_string(),
+            kind: EntryKind::File {
+                hash: "123".to_string(),
+            },
+            attributes: attributes(0o755),
+        };
+        entry
+            .attributes
+            .xattrs
+            .insert("security.capability".to_string(), vec![1, 0xff]);
+
+        let json = serde_json::to_value(&entry).unwrap();
+        assert_eq!(json["xattrs"]["security.capability"], "01ff");
+        assert_eq!(serde_json::from_value::<Entry>(json).unwrap(), entry);
+    }
+
+    #[test]
+    fn validate_rejects_invalid_xattrs() {
+        let manifest = |path: &str, kind: EntryKind, name: &str| {
+            Manifest::new(vec![Entry {
+                path: path.to_string(),
+                kind,
+                attributes: Attributes {
+                    xattrs: Xattrs::from([(name.to_string(), vec![])]),
+                    ..attributes(0o644)
+                },
+            }])
+        };
+
+        assert!(
+            manifest("/a", EntryKind::Directory, "user.a")
+                .validate()
+                .is_ok()
+        );
+        assert!(
+            manifest("/a", EntryKind::Directory, "nonamespace")
+                .validate()
+                .is_err()
+        );
+        assert!(
+            manifest("/a", EntryKind::Directory, "user.\0")
+                .validate()
+                .is_err()
+        );
+        let symlink = EntryKind::Symlink {
+            target: "b".to_string(),
+        };
+        assert!(manifest("/a", symlink, "user.a").validate().is_err());
+    }
+
+    #[test]
+    fn validate_rejects_dangling_hardlinks() {
+        let file = Entry {
+            path: "/a".to_string(),
+            kind: EntryKind::File {
+                hash: "123".to_string(),
+            },
+            attributes: attributes(0o644),
+        };
+        let hardlink = |target: &str, mode: u32| Entry {
+            path: "/b".to_string(),
+            kind: EntryKind::Hardlink {
+                target: target.to_string(),
+            },
+            attributes: attributes(mode),
+        };
+
+        assert!(
+            Manifest::new(vec![file.clone(), hardlink("/a", 0o644)])
+                .validate()
+                .is_ok()
+        );
+        assert!(
+            Manifest::new(vec![file.clone(), hardlink("/c", 0o644)])
+                .validate()
+                .is_err()
+        );
+        assert!(
+            Manifest::new(vec![file.clone(), hardlink("/a", 0o755)])
+                .validate()
+                .is_err()
+        );
+        // Hard links to hard links aren't files
+        let mut chained = hardlink("/b", 0o644);
+        chained.path = "/c".to_string();
+        assert!(
+            Manifest::new(vec![file, hardlink("/a", 0o644), chained])
+                .validate()
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn parse_special_files() {
+        let manifest = Manifest::parse(
+            r#"{"entries": [
+                {"path": "/dev/null", "type": "char_device", "major": 1, "minor": 3, "mode": 438, "uid": 0, "gid": 0, "mtime": 0},
+                {"path": "/dev/initctl", "type": "fifo", "mode": 384, "uid": 0, "gid": 0, "mtime": 0}
+            ], "format": 2}"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            manifest.entries[0].kind,
+            EntryKind::CharDevice { major: 1, minor: 3 }
+        );
+        assert!(manifest.entries[0].kind.is_device());
+        assert_eq!(manifest.entries[1].kind, EntryKind::Fifo);
+        assert!(!manifest.entries[1].kind.is_device());
+    }
+
+    #[test]
+    #[cfg(feature = "encoding")]
+    fn decode_binary_round_trip() {
+        use crate::hash::{HashAlgorithm, hash_manifest};
+
+        let mut manifest = Manifest {
+            chunks: BTreeMap::from([("123".to_string(), vec!["4".to_string(), "5".to_string()])]),
+            sizes: BTreeMap::from([("123".to_string(), 1024)]),
+            chunk_sizes: BTreeMap::from([("4".to_string(), 100), ("5".to_string(), 200)]),
+            ..Manifest::new(vec![
+                Entry {
+                    path: "/bin/su".to_string(),
+                    kind: EntryKind::File {
+                        hash: "123".to_string(),
+                    },
+                    attributes: attributes(0o4755),
+                },
+                Entry {
+                    path: "/dev/null".to_string(),
+                    kind: EntryKind::CharDevice { major: 1, minor: 3 },
+                    attributes: attributes(0o666),
+                },
+            ])
+        };
+        manifest.entries[0]
+            .attributes
+            .xattrs
+            .insert("security.capability".to_string(), vec![0, 1, 2]);
+        let encoded = manifest.encode();
+        let hash = hash_manifest(HashAlgorithm::Blake3, &manifest);
+
+        assert!(encoded.starts_with(&crate::compression::MAGIC));
+        assert_eq!(
+            Manifest::decode_verified(&encoded, &hash).unwrap(),
+            manifest
+        );
+
+        let with_root = Manifest {
+            root: Some("789".to_string()),
+            metadata: Metadata {
+                commit: Some("0123abc".to_string()),
+                build_time: Some(1_700_000_000),
+                extra: BTreeMap::from([("channel".to_string(), "stable".to_string())]),
+                ..Metadata::default()
+            },
+            ..Manifest::new(Vec::new())
+        };
+        assert_eq!(Manifest::decode(&with_root.encode()).unwrap(), with_root);
+    }
+
+    #[test]
+    #[cfg(feature = "encoding")]
+    fn metadata_is_hashed() {
+        use crate::hash::{HashAlgorithm, hash_manifest};
+
+        let mut manifest = Manifest::new(Vec::new());
+        let empty = hash_manifest(HashAlgorithm::Xxh3, &manifest);
+        manifest.metadata.job = Some("1234".to_string());
+        let with_job = hash_manifest(HashAlgorithm::Xxh3, &manifest);
+        manifest.metadata.job = None;
+        manifest.metadata.toolchain = Some("1234".to_string());
+
+        assert_ne!(empty, with_job);
+        assert_ne!(with_job, hash_manifest(HashAlgorithm::Xxh3, &manifest));
+    }
+
+    #[test]
+    #[cfg(feature = "encoding")]
+    fn decode_rejects_malformed_binary() {
+        use crate::compression::compress_file;
+
+        let manifest = Manifest::new(Vec::new());
+        let bytes = manifest.canonical_bytes();
+
+        assert!(Manifest::decode(&compress_file(&bytes[..bytes.len() - 1], 3)).is_err());
+        assert!(Manifest::decode(&compress_file(&[bytes.as_slice(), &[0]].concat(), 3)).is_err());
+        // Format 1 was never binary
+        let mut old = bytes.clone();
+        old[0] = 1;
+        assert!(Manifest::decode(&compress_file(&old, 3)).is_err());
+        // Not a valid zstd frame after the magic number
+        assert!(Manifest::decode(&crate::compression::MAGIC).is_err());
+    }
+
+    #[test]
+    fn decode_verified_checks_hash() {
+        use crate::hash::{HashAlgorithm, hash_manifest};
+
+        let manifest = Manifest::new(vec![Entry {
+            path: "/a".to_string(),
+            kind: EntryKind::File {
+                hash: "123".to_string(),
+            },
+            attributes: attributes(0o644),
+        }]);
+        let json = serde_json::to_string(&manifest).unwrap();
+        let hash = hash_manifest(HashAlgorithm::Blake3, &manifest);
+
+        assert_eq!(
+            Manifest::decode_verified(json.as_bytes(), &hash).unwrap(),
+            manifest
+        );
+        let tampered = json.replace("123", "456");
+        assert!(Manifest::decode_verified(tampered.as_bytes(), &hash).is_err());
+
+        // Format 1 is checked the way it was hashed, and only ever under an xxh3 name
+        let legacy = r#"{"files": [["/a", "123", true]], "format": 1}"#;
+        let legacy_hash = crate::hash::hash(HashAlgorithm::Xxh3, b"/a123true");
+        assert_eq!(
+            Manifest::decode_verified(legacy.as_bytes(), &legacy_hash)
+                .unwrap()
+                .format,
+            FORMAT
+        );
+        assert!(Manifest::decode_verified(legacy.as_bytes(), "123").is_err());
+        assert!(Manifest::decode_verified(legacy.as_bytes(), &hash).is_err());
+        let blake3_hash = crate::hash::hash(HashAlgorithm::Blake3, b"/a123true");
+        assert!(Manifest::decode_verified(legacy.as_bytes(), &blake3_hash).is_err());
+    }
+
+    #[test]
+    fn decode_verified_rejects_fields_format_1_didnt_hash() {
+        use crate::hash::{HashAlgorithm, hash_legacy_manifest};
+
+        let legacy_hash = crate::hash::hash(HashAlgorithm::Xxh3, b"/a123true");
+        let empty_hash = hash_legacy_manifest(std::iter::empty());
+        for injected in [
+            r#""metadata": {"commit": "0123abc"}"#,
+            r#""chunks": {"123": ["4", "5"]}"#,
+            r#""sizes": {"123": 1024}"#,
+            r#""chunk_sizes": {"4": 100}"#,
+            r#""root": "555""#,
+            r#""entries": []"#,
+        ] {
+            let legacy = format!(r#"{{"files": [["/a", "123", true]], {injected}, "format": 1}}"#);
+            assert!(Manifest::decode_verified(legacy.as_bytes(), &legacy_hash).is_err());
+
+            // An empty manifest's hash is a constant anyone can name
+            let empty = format!(r#"{{"files": [], {injected}, "format": 1}}"#);
+            assert!(Manifest::decode_verified(empty.as_bytes(), &empty_hash).is_err());
+        }
+    }
+
+    #[test]
+    fn effective_mode_drops_setuid_when_unprivileged() {
+        assert_eq!(attributes(0o6755).effective_mode(true), 0o6755);
+        assert_eq!(attributes(0o6755).effective_mode(false), 0o755);
+        assert_eq!(attributes(0o1777).effective_mode(false), 0o1777);
+    }
+
+    #[test]
+    fn effective_xattrs_keeps_only_user_when_unprivileged() {
+        let attributes = Attributes {
+            xattrs: Xattrs::from([
+                ("security.selinux".to_string(), vec![1]),
+                ("user.comment".to_string(), vec![2]),
+            ]),
+            ..attributes(0o644)
+        };
+
+        assert_eq!(attributes.effective_xattrs(true).count(), 2);
+        let unprivileged: Vec<_> = attributes
+            .effective_xattrs(false)
+            .map(|(name, _)| name)
+            .collect();
+        assert_eq!(unprivileged, vec!["user.comment"]);
+    }
+}
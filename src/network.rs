@@ -7,7 +7,8 @@ use std::path::Path;
 pub fn download_file(url: &str, target_location: &Path) -> Result<u64> {
     use std::{fs::File, io::Write};
 
-    let response = reqwest::blocking::get(url)?;
+    // Otherwise a missing file would be saved as the server's error page
+    let response = reqwest::blocking::get(url)?.error_for_status()?;
     let content = response.bytes()?;
 
     let mut dest = File::create(target_location)?;
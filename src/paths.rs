@@ -0,0 +1,294 @@
+#![warn(clippy::pedantic)]
+
+use crate::manifest::Attributes;
+use anyhow::{Result, bail};
+use rustix::fs::{
+    AtFlags, CWD, Dev, FileType, Gid, Mode, OFlags, Timespec, Timestamps, Uid, chmodat, chownat,
+    fchmod, fchown, futimens, mkdirat, mknodat, openat, statat, symlinkat, utimensat,
+};
+use rustix::io::Errno;
+use std::collections::HashSet;
+use std::os::fd::OwnedFd;
+use std::path::Path;
+
+// Splits a manifest path into its components, rejecting anything that could escape the artifact's root.
+// Manifest paths are relative to the artifact, and `build` writes them with a single leading `/`.
+pub fn components(path: &str) -> Result<Vec<&str>> {
+    if path.contains('\0') {
+        bail!("Manifest path {path:?} contains a NUL byte");
+    }
+
+    let relative = path.strip_prefix('/').unwrap_or(path);
+    if relative.is_empty() {
+        bail!("Manifest path {path:?} is empty");
+    }
+
+    let components: Vec<&str> = relative.split('/').collect();
+    for component in &components {
+        match *component {
+            "" => bail!("Manifest path {path:?} is absolute or has an empty component"),
+            "." | ".." => bail!("Manifest path {path:?} contains a {component:?} component"),
+            _ => {}
+        }
+    }
+
+    Ok(components)
+}
+
+// Checks every path in a manifest is safe to install, and that none of them are listed twice
+pub fn validate<'a>(paths: impl IntoIterator<Item = &'a str>) -> Result<()> {
+    let mut seen = HashSet::new();
+
+    for path in paths {
+        if !seen.insert(components(path)?) {
+            bail!("Manifest path {path:?} is listed more than once");
+        }
+    }
+
+    Ok(())
+}
+
+/// The root directory of an artifact being installed.
+///
+/// Everything is created relative to the root's file descriptor, and symlinks are never followed on the way,
+/// so neither a malicious manifest nor a concurrent change to the tree can place anything outside of it.
+pub struct Root {
+    fd: OwnedFd,
+}
+
+impl Root {
+    pub fn open(path: &Path) -> Result<Self> {
+        let fd = openat(CWD, path, directory_flags(), Mode::empty())?;
+        Ok(Self { fd })
+    }
+
+    // Opens the directory that will hold `path`, creating any missing directories on the way
+    fn parent_of<'a>(&self, path: &'a str) -> Result<(OwnedFd, &'a str)> {
+        let mut components = components(path)?;
+        let name = components.pop().unwrap_or_default();
+
+        let mut dir = rustix::io::dup(&self.fd)?;
+        for component in components {
+            match mkdirat(&dir, component, Mode::from_raw_mode(0o755)) {
+                Ok(()) | Err(Errno::EXIST) => {}
+                Err(e) => return Err(e.into()),
+            }
+
+            // Fails if `component` was swapped for a symlink, rather than following it out of the tree
+            dir = openat(&dir, component, directory_flags(), Mode::empty())
+                .map_err(|e| anyhow::anyhow!("Couldn't open {component:?} of {path:?}: {e}"))?;
+        }
+
+        Ok((dir, name))
+    }
+
+    // Creates a symlink at `path` pointing to `target`. Existing entries are left untouched.
+    pub fn symlink(&self, target: &Path, path: &str) -> Result<()> {
+        let (dir, name) = self.parent_of(path)?;
+
+        match symlinkat(target, &dir, name) {
+            Ok(()) | Err(Errno::EXIST) => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    // Creates a directory at `path`, or does nothing if it already exists
+    pub fn create_dir(&self, path: &str) -> Result<()> {
+        let (dir, name) = self.parent_of(path)?;
+
+        match mkdirat(&dir, name, Mode::from_raw_mode(0o755)) {
+            Ok(()) | Err(Errno::EXIST) => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    // Creates a device node, FIFO or socket at `path`. Existing entries are left untouched.
+    // The mode is left for `set_attributes`.
+    pub fn create_special(&self, path: &str, file_type: FileType, dev: Dev) -> Result<()> {
+        let (dir, name) = self.parent_of(path)?;
+
+        match mknodat(&dir, name, file_type, Mode::from_raw_mode(0o600), dev) {
+            Ok(()) | Err(Errno::EXIST) => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    // Applies ownership, mode and modification time to the entry at `path`, without following it if it's a symlink.
+    // Ownership is only applied when `privileged`, see `Attributes::effective_mode`.
+    pub fn set_attributes(
+        &self,
+        path: &str,
+        attributes: &Attributes,
+        privileged: bool,
+    ) -> Result<()> {
+        let (dir, name) = self.parent_of(path)?;
+        let owner = Some(Uid::from_raw(attributes.uid));
+        let group = Some(Gid::from_raw(attributes.gid));
+
+        // Symlinks can't be opened without following them, and opening a FIFO or device could block or have side effects
+        let stat = statat(&dir, name, AtFlags::SYMLINK_NOFOLLOW)?;
+        let file_type = FileType::from_raw_mode(stat.st_mode);
+        if !matches!(file_type, FileType::Directory | FileType::RegularFile) {
+            if privileged {
+                chownat(&dir, name, owner, group, AtFlags::SYMLINK_NOFOLLOW)?;
+            }
+            // Symlinks have no mode of their own
+            if file_type != FileType::Symlink {
+                chmodat(
+                    &dir,
+                    name,
+                    Mode::from_raw_mode(attributes.effective_mode(privileged)),
+                    AtFlags::empty(),
+                )?;
+            }
+            utimensat(
+                &dir,
+                name,
+                &timestamps(attributes.mtime),
+                AtFlags::SYMLINK_NOFOLLOW,
+            )?;
+            return Ok(());
+        }
+
+        // Fails if `name` was swapped for a symlink since it was checked
+        let fd = openat(
+            &dir,
+            name,
+            OFlags::RDONLY | OFlags::NOFOLLOW | OFlags::CLOEXEC,
+            Mode::empty(),
+        )
+        .map_err(|e| anyhow::anyhow!("Couldn't open {path:?}: {e}"))?;
+
+        // Changing the owner clears setuid and setgid, so the mode has to be applied afterwards
+        if privileged {
+            fchown(&fd, owner, group)?;
+        }
+        fchmod(
+            &fd,
+            Mode::from_raw_mode(attributes.effective_mode(privileged)),
+        )?;
+        crate::xattrs::apply(&fd, attributes.effective_xattrs(privileged))?;
+        futimens(&fd, &timestamps(attributes.mtime))?;
+
+        Ok(())
+    }
+}
+
+// Access and modification times are both set to `mtime`
+pub fn timestamps(mtime: i64) -> Timestamps {
+    let time = Timespec {
+        tv_sec: mtime,
+        tv_nsec: 0,
+    };
+
+    Timestamps {
+        last_access: time,
+        last_modification: time,
+    }
+}
+
+fn directory_flags() -> OFlags {
+    OFlags::RDONLY | OFlags::DIRECTORY | OFlags::NOFOLLOW | OFlags::CLOEXEC
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env::temp_dir;
+    use std::fs;
+
+    #[test]
+    fn components_of_valid_paths() {
+        assert_eq!(components("/a/b/c").unwrap(), vec!["a", "b", "c"]);
+        assert_eq!(components("a/b").unwrap(), vec!["a", "b"]);
+        assert_eq!(components("/.hidden").unwrap(), vec![".hidden"]);
+    }
+
+    #[test]
+    fn components_rejects_traversal() {
+        assert!(components("/../../etc/passwd").is_err());
+        assert!(components("/a/../../b").is_err());
+        assert!(components("/a/./b").is_err());
+        assert!(components("//etc/passwd").is_err());
+        assert!(components("/a//b").is_err());
+        assert!(components("/a/").is_err());
+        assert!(components("/").is_err());
+        assert!(components("").is_err());
+        assert!(components("/a\0b").is_err());
+    }
+
+    #[test]
+    fn validate_rejects_duplicates() {
+        assert!(validate(["/a", "/b", "/c/a"]).is_ok());
+        assert!(validate(["/a", "/b", "/a"]).is_err());
+        assert!(validate(["/a", "a"]).is_err());
+    }
+
+    #[test]
+    fn symlink_creates_parents() {
+        let dir = temp_dir().join("lcas_paths_symlink_test");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        let root = Root::open(&dir).unwrap();
+        root.symlink(Path::new("/target"), "/a/b/c").unwrap();
+
+        assert_eq!(
+            fs::read_link(dir.join("a/b/c")).unwrap(),
+            Path::new("/target")
+        );
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn symlink_does_not_follow_symlinked_parents() {
+        let dir = temp_dir().join("lcas_paths_symlink_escape_test");
+        let outside = temp_dir().join("lcas_paths_symlink_escape_test_outside");
+        let _ = fs::remove_dir_all(&dir);
+        let _ = fs::remove_dir_all(&outside);
+        fs::create_dir_all(&dir).unwrap();
+        fs::create_dir_all(&outside).unwrap();
+
+        // Something swapped a directory in the tree for a symlink leading out of it
+        std::os::unix::fs::symlink(&outside, dir.join("a")).unwrap();
+
+        let root = Root::open(&dir).unwrap();
+        assert!(root.symlink(Path::new("/target"), "/a/escaped").is_err());
+        assert!(!outside.join("escaped").exists());
+
+        let _ = fs::remove_dir_all(&dir);
+        let _ = fs::remove_dir_all(&outside);
+    }
+
+    #[test]
+    fn create_special_makes_fifo() {
+        use std::os::unix::fs::FileTypeExt;
+
+        let dir = temp_dir().join("lcas_paths_fifo_test");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        let root = Root::open(&dir).unwrap();
+        root.create_special("/run/pipe", FileType::Fifo, 0).unwrap();
+        // Setting attributes mustn't open it, which would block without a writer
+        let attributes = Attributes {
+            mode: 0o620,
+            uid: 0,
+            gid: 0,
+            mtime: 0,
+            xattrs: crate::manifest::Xattrs::new(),
+        };
+        root.set_attributes("/run/pipe", &attributes, false)
+            .unwrap();
+
+        let metadata = fs::symlink_metadata(dir.join("run/pipe")).unwrap();
+        assert!(metadata.file_type().is_fifo());
+        assert_eq!(
+            std::os::unix::fs::PermissionsExt::mode(&metadata.permissions()) & 0o7777,
+            0o620
+        );
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
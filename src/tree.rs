@@ -0,0 +1,382 @@
+#![warn(clippy::pedantic)]
+
+// Directory trees, in the style of git. Every directory is its own content-addressed tree, listing only what's
+// directly inside it, so a subtree shared by several artifacts is only stored (and installed) once.
+
+use crate::manifest::Entry;
+use std::collections::BTreeMap;
+
+#[cfg(any(feature = "decoding", feature = "encoding"))]
+use crate::manifest::{EntryKind, Manifest};
+#[cfg(any(feature = "decoding", feature = "encoding"))]
+use anyhow::{Result, bail};
+
+/// Trees nested deeper than this are rejected, rather than risking a cycle between crafted hashes.
+#[cfg(any(feature = "decoding", feature = "encoding"))]
+pub const MAX_DEPTH: usize = 1024;
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct Tree {
+    /// Everything directly inside this directory, with names rather than paths. Subdirectories are `Tree` entries.
+    pub entries: Vec<Entry>,
+    /// Files directly inside this directory that were split, as in `Manifest::chunks`.
+    pub chunks: BTreeMap<String, Vec<String>>,
+    /// Sizes of the files directly inside this directory, as in `Manifest::sizes`.
+    pub sizes: BTreeMap<String, u64>,
+    /// Sizes of the chunks of the files directly inside this directory, as in `Manifest::chunk_sizes`.
+    pub chunk_sizes: BTreeMap<String, u64>,
+}
+
+// Leads every tree's encoding in place of a manifest's format, so a tree can never share a manifest's hash
+const TAG: u8 = u8::MAX;
+
+impl Tree {
+    // Encoded like a manifest, but behind its own leading byte
+    #[cfg(feature = "encoding")]
+    pub fn canonical_bytes(&self) -> Vec<u8> {
+        use crate::manifest::{encode_chunks, encode_entries, encode_sizes};
+
+        let mut encoder = crate::binary::Encoder::default();
+        encoder.u8(TAG);
+        encode_entries(&mut encoder, &self.entries);
+        encode_chunks(&mut encoder, &self.chunks);
+        encode_sizes(&mut encoder, &self.sizes);
+        encode_sizes(&mut encoder, &self.chunk_sizes);
+
+        encoder.finish()
+    }
+
+    // The bytes written to a repo, compressed like a manifest's
+    #[cfg(feature = "encoding")]
+    pub fn encode(&self) -> Vec<u8> {
+        crate::compression::compress_file(&self.canonical_bytes(), 3)
+    }
+
+    // Reads back a tree written by `encode`, and checks it's the one named by `hash`
+    pub fn decode_verified(tree: &[u8], hash: &str) -> Result<Self> {
+        use crate::hash::HashAlgorithm;
+        use crate::manifest::{decode_chunks, decode_entries, decode_sizes};
+
+        let bytes = crate::compression::try_decompress(tree)?;
+        if crate::hash::hash(HashAlgorithm::of(hash)?, &bytes) != hash {
+            bail!("Tree doesn't match its hash {hash:?}");
+        }
+
+        let mut decoder = crate::binary::Decoder::new(&bytes);
+        if decoder.u8()? != TAG {
+            bail!("Tree {hash} isn't a tree");
+        }
+        let tree = Self {
+            entries: decode_entries(&mut decoder)?,
+            chunks: decode_chunks(&mut decoder)?,
+            sizes: decode_sizes(&mut decoder)?,
+            chunk_sizes: decode_sizes(&mut decoder)?,
+        };
+        decoder.finish()?;
+
+        Ok(tree)
+    }
+}
+
+// Splits a flat manifest with sorted entries into one tree per directory, writing every tree the repo doesn't have
+// yet. Returns the same manifest, referring to the root tree instead of listing its entries.
+#[cfg(feature = "encoding")]
+pub fn write_trees(
+    manifest: crate::manifest::Manifest,
+    algorithm: crate::HashAlgorithm,
+    tree_dir: &std::path::Path,
+) -> Result<crate::manifest::Manifest> {
+    use crate::manifest::{EntryKind, Manifest};
+    use std::collections::HashMap;
+
+    std::fs::create_dir_all(tree_dir)?;
+
+    // Entries of every directory by its path, with the root as ""
+    let mut directories: HashMap<String, Vec<Entry>> = HashMap::from([(String::new(), Vec::new())]);
+    for mut entry in manifest.entries {
+        let Some((parent, name)) = entry.path.rsplit_once('/') else {
+            bail!("Manifest path {:?} isn't absolute", entry.path);
+        };
+        let parent = parent.to_string();
+        let name = name.to_string();
+
+        if entry.kind == EntryKind::Directory {
+            directories.entry(entry.path.clone()).or_default();
+        }
+
+        entry.path = name;
+        directories.entry(parent).or_default().push(entry);
+    }
+
+    // Deepest first, so every subtree is hashed before the tree that refers to it
+    let mut paths: Vec<String> = directories.keys().cloned().collect();
+    paths.sort_by_key(|path| std::cmp::Reverse(path.matches('/').count()));
+
+    let mut hashes = HashMap::new();
+    for path in paths {
+        let mut entries = directories.remove(&path).unwrap_or_default();
+        let mut tree_chunks = BTreeMap::new();
+        let mut sizes = BTreeMap::new();
+        let mut chunk_sizes = BTreeMap::new();
+
+        for entry in &mut entries {
+            match &entry.kind {
+                EntryKind::Directory => {
+                    let hash = hashes
+                        .remove(&format!("{path}/{}", entry.path))
+                        .ok_or_else(|| anyhow::anyhow!("Missing tree for {:?}", entry.path))?;
+                    entry.kind = EntryKind::Tree { hash };
+                }
+                EntryKind::File { hash } => {
+                    let chunk_hashes = manifest.chunks.get(hash);
+                    if let Some(chunk_hashes) = chunk_hashes {
+                        tree_chunks.insert(hash.clone(), chunk_hashes.clone());
+                    }
+                    if let Some(size) = manifest.sizes.get(hash) {
+                        sizes.insert(hash.clone(), *size);
+                    }
+                    for chunk_hash in chunk_hashes.map_or(std::slice::from_ref(hash), Vec::as_slice)
+                    {
+                        if let Some(size) = manifest.chunk_sizes.get(chunk_hash) {
+                            chunk_sizes.insert(chunk_hash.clone(), *size);
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        let tree = Tree {
+            entries,
+            chunks: tree_chunks,
+            sizes,
+            chunk_sizes,
+        };
+        let hash = crate::hash::hash(algorithm, &tree.canonical_bytes());
+
+        // Shared with every other artifact containing the same directory
+        crate::atomic::write_new(&tree_dir.join(&hash), &tree.encode())?;
+
+        hashes.insert(path, hash);
+    }
+
+    let root = hashes
+        .remove("")
+        .ok_or_else(|| anyhow::anyhow!("Missing the root tree"))?;
+
+    Ok(Manifest {
+        entries: Vec::new(),
+        chunks: BTreeMap::new(),
+        sizes: BTreeMap::new(),
+        chunk_sizes: BTreeMap::new(),
+        root: Some(root),
+        metadata: manifest.metadata,
+        format: manifest.format,
+    })
+}
+
+/// A manifest read back out of its trees.
+#[cfg(any(feature = "decoding", feature = "encoding"))]
+pub struct Flattened {
+    /// A flat manifest with the same entries as the trees.
+    pub manifest: Manifest,
+    /// Every tree that was read, with every subtree before the tree containing it.
+    pub trees: Vec<FlatTree>,
+}
+
+/// Where a tree's entries ended up in a [`Flattened`] manifest.
+#[cfg(any(feature = "decoding", feature = "encoding"))]
+pub struct FlatTree {
+    pub hash: String,
+    /// The path of the directory it's for, which is empty for the root.
+    #[cfg(feature = "decoding")]
+    pub path: String,
+    /// Indices of the entries directly inside it. Its subtrees are `Directory` entries.
+    #[cfg(feature = "decoding")]
+    pub entries: Vec<usize>,
+}
+
+// Reads every tree below `root` into a single flat manifest, fetching each with `read`.
+// Every tree is checked against its hash before it's used.
+#[cfg(any(feature = "decoding", feature = "encoding"))]
+pub fn flatten(root: &str, read: &mut impl FnMut(&str) -> Result<Vec<u8>>) -> Result<Flattened> {
+    let mut flattened = Flattened {
+        manifest: Manifest::new(Vec::new()),
+        trees: Vec::new(),
+    };
+
+    flatten_into(root, "", 0, read, &mut flattened)?;
+
+    Ok(flattened)
+}
+
+#[cfg(any(feature = "decoding", feature = "encoding"))]
+fn flatten_into(
+    hash: &str,
+    prefix: &str,
+    depth: usize,
+    read: &mut impl FnMut(&str) -> Result<Vec<u8>>,
+    flattened: &mut Flattened,
+) -> Result<()> {
+    if depth > MAX_DEPTH {
+        bail!("Trees are nested more than {MAX_DEPTH} deep");
+    }
+
+    // Checked before it's used in a path
+    crate::hash::HashAlgorithm::of(hash)?;
+    let tree = Tree::decode_verified(&read(hash)?, hash)?;
+    let mut entries = Vec::new();
+
+    for entry in tree.entries {
+        // A single path component, so a tree can only hold what's directly inside it
+        let name = entry.path.as_str();
+        if name.is_empty() || name == "." || name == ".." || name.contains(['/', '\0']) {
+            bail!("Tree {hash} has an invalid name {:?}", entry.path);
+        }
+        let path = format!("{prefix}/{}", entry.path);
+        entries.push(flattened.manifest.entries.len());
+
+        if let EntryKind::Tree { hash: subtree } = &entry.kind {
+            flattened.manifest.entries.push(Entry {
+                path: path.clone(),
+                kind: EntryKind::Directory,
+                attributes: entry.attributes,
+            });
+            flatten_into(subtree, &path, depth + 1, read, flattened)?;
+            continue;
+        }
+
+        flattened.manifest.entries.push(Entry { path, ..entry });
+    }
+
+    flattened.manifest.chunks.extend(tree.chunks);
+    flattened.manifest.sizes.extend(tree.sizes);
+    flattened.manifest.chunk_sizes.extend(tree.chunk_sizes);
+    flattened.trees.push(FlatTree {
+        hash: hash.to_string(),
+        #[cfg(feature = "decoding")]
+        path: prefix.to_string(),
+        #[cfg(feature = "decoding")]
+        entries,
+    });
+
+    Ok(())
+}
+
+#[cfg(test)]
+#[cfg(all(feature = "encoding", feature = "decoding"))]
+mod tests {
+    use super::*;
+    use crate::HashAlgorithm;
+    use crate::manifest::{Attributes, EntryKind, Xattrs};
+    use std::env::temp_dir;
+    use std::fs;
+
+    fn entry(path: &str, kind: EntryKind) -> Entry {
+        Entry {
+            path: path.to_string(),
+            kind,
+            attributes: Attributes {
+                mode: 0o755,
+                uid: 0,
+                gid: 0,
+                mtime: 0,
+                xattrs: Xattrs::new(),
+            },
+        }
+    }
+
+    fn file(path: &str, hash: &str) -> Entry {
+        entry(
+            path,
+            EntryKind::File {
+                hash: hash.to_string(),
+            },
+        )
+    }
+
+    #[test]
+    fn trees_round_trip() {
+        let tree_dir = temp_dir().join("lcas_tree_round_trip_test");
+        let _ = fs::remove_dir_all(&tree_dir);
+
+        let entries = vec![
+            entry("/usr", EntryKind::Directory),
+            entry("/usr/share", EntryKind::Directory),
+            file("/usr/share/a", "1"),
+            file("/usr/share/b", "2"),
+            file("/z", "3"),
+        ];
+        let chunks = BTreeMap::from([("2".to_string(), vec!["4".to_string(), "5".to_string()])]);
+        let sizes = |sizes: &[(&str, u64)]| -> BTreeMap<String, u64> {
+            sizes
+                .iter()
+                .map(|(hash, size)| ((*hash).to_string(), *size))
+                .collect()
+        };
+        let file_sizes = sizes(&[("1", 10), ("2", 20), ("3", 30)]);
+        let chunk_sizes = sizes(&[("1", 5), ("3", 8), ("4", 6), ("5", 7)]);
+
+        let manifest = Manifest {
+            chunks: chunks.clone(),
+            sizes: file_sizes.clone(),
+            chunk_sizes: chunk_sizes.clone(),
+            ..Manifest::new(entries.clone())
+        };
+        let root = write_trees(manifest, HashAlgorithm::Xxh3, &tree_dir)
+            .unwrap()
+            .root
+            .unwrap();
+        assert_eq!(fs::read_dir(&tree_dir).unwrap().count(), 3);
+
+        let flattened = flatten(&root, &mut |hash| Ok(fs::read(tree_dir.join(hash))?)).unwrap();
+        assert_eq!(flattened.manifest.entries, entries);
+        assert_eq!(flattened.manifest.chunks, chunks);
+        assert_eq!(flattened.manifest.sizes, file_sizes);
+        assert_eq!(flattened.manifest.chunk_sizes, chunk_sizes);
+        let trees: Vec<(&str, usize)> = flattened
+            .trees
+            .iter()
+            .map(|tree| (tree.path.as_str(), tree.entries.len()))
+            .collect();
+        assert_eq!(trees, vec![("/usr/share", 2), ("/usr", 1), ("", 2)]);
+
+        let _ = fs::remove_dir_all(&tree_dir);
+    }
+
+    #[test]
+    fn flatten_rejects_invalid_names() {
+        for name in ["..", "a/b", "/a", ""] {
+            let tree = Tree {
+                entries: vec![file(name, "1")],
+                chunks: BTreeMap::new(),
+                sizes: BTreeMap::new(),
+                chunk_sizes: BTreeMap::new(),
+            };
+            let hash = crate::hash::hash(HashAlgorithm::Xxh3, &tree.canonical_bytes());
+
+            assert!(flatten(&hash, &mut |_| Ok(tree.encode())).is_err());
+        }
+    }
+
+    #[test]
+    fn flatten_rejects_tampered_trees() {
+        let tree = Tree {
+            entries: vec![file("a", "1")],
+            chunks: BTreeMap::new(),
+            sizes: BTreeMap::new(),
+            chunk_sizes: BTreeMap::new(),
+        };
+        let hash = crate::hash::hash(HashAlgorithm::Xxh3, &tree.canonical_bytes());
+        let tampered = Tree {
+            entries: vec![file("a", "2")],
+            ..tree
+        };
+
+        assert!(flatten(&hash, &mut |_| Ok(tampered.encode())).is_err());
+        // A manifest can't be read back as a tree, even under its own hash
+        let manifest = Manifest::new(Vec::new());
+        let hash = crate::hash::hash_manifest(HashAlgorithm::Xxh3, &manifest);
+        assert!(flatten(&hash, &mut |_| Ok(manifest.encode())).is_err());
+    }
+}
@@ -0,0 +1,147 @@
+#![warn(clippy::pedantic)]
+
+// Extended attributes, such as file capabilities and SELinux labels.
+
+#[cfg(feature = "encoding")]
+use crate::manifest::Xattrs;
+use anyhow::Result;
+#[cfg(feature = "encoding")]
+use anyhow::bail;
+#[cfg(feature = "encoding")]
+use rustix::io::Errno;
+#[cfg(feature = "encoding")]
+use std::path::Path;
+
+// Checks whether `name` is in one of `namespaces`. An entry may also name a single attribute (e.g. `security.capability`).
+#[cfg(feature = "encoding")]
+pub fn allowed(name: &str, namespaces: &[String]) -> bool {
+    namespaces.iter().any(|namespace| {
+        name.strip_prefix(namespace.as_str())
+            .is_some_and(|rest| rest.is_empty() || rest.starts_with('.'))
+    })
+}
+
+// Reads every extended attribute of `path` in `namespaces`, without following symlinks
+#[cfg(feature = "encoding")]
+pub fn read(path: &Path, namespaces: &[String]) -> Result<Xattrs> {
+    use rustix::fs::{lgetxattr, llistxattr};
+
+    let mut xattrs = Xattrs::new();
+    if namespaces.is_empty() {
+        return Ok(xattrs);
+    }
+
+    let names = match read_sized(|buf| llistxattr(path, buf)) {
+        Ok(names) => names,
+        // Filesystems without xattr support simply have none
+        Err(Errno::NOTSUP) => return Ok(xattrs),
+        Err(e) => return Err(e.into()),
+    };
+
+    for name in names.split(|b| *b == 0).filter(|name| !name.is_empty()) {
+        let Ok(name) = String::from_utf8(name.to_vec()) else {
+            bail!("{} has an xattr that isn't UTF-8", path.display());
+        };
+        if !allowed(&name, namespaces) {
+            continue;
+        }
+
+        match read_sized(|buf| lgetxattr(path, name.as_str(), buf)) {
+            Ok(value) => {
+                xattrs.insert(name, value);
+            }
+            // Removed since it was listed
+            Err(Errno::NODATA) => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    Ok(xattrs)
+}
+
+// Calls `read` with a large enough buffer, retrying if the value grew in between
+#[cfg(feature = "encoding")]
+fn read_sized(
+    read: impl Fn(&mut [u8]) -> rustix::io::Result<usize>,
+) -> rustix::io::Result<Vec<u8>> {
+    loop {
+        let mut buf = vec![0; read(&mut [])?];
+
+        match read(&mut buf) {
+            Ok(len) => {
+                buf.truncate(len);
+                return Ok(buf);
+            }
+            Err(Errno::RANGE) => {}
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+// Sets every given xattr on `fd`, replacing any existing value
+#[cfg(feature = "decoding")]
+pub fn apply<'a>(
+    fd: impl std::os::fd::AsFd,
+    xattrs: impl Iterator<Item = (&'a String, &'a Vec<u8>)>,
+) -> Result<()> {
+    use rustix::fs::{XattrFlags, fsetxattr};
+
+    for (name, value) in xattrs {
+        fsetxattr(&fd, name.as_str(), value, XattrFlags::empty())
+            .map_err(|e| anyhow::anyhow!("Couldn't set xattr {name:?}: {e}"))?;
+    }
+
+    Ok(())
+}
+
+// Sets every given xattr on `path` like `apply`, for files that aren't open. Symlinks aren't followed.
+#[cfg(feature = "decoding")]
+pub fn apply_path<'a>(
+    path: &std::path::Path,
+    xattrs: impl Iterator<Item = (&'a String, &'a Vec<u8>)>,
+) -> Result<()> {
+    use rustix::fs::{XattrFlags, lsetxattr};
+
+    for (name, value) in xattrs {
+        lsetxattr(path, name.as_str(), value, XattrFlags::empty())
+            .map_err(|e| anyhow::anyhow!("Couldn't set xattr {name:?}: {e}"))?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    #[cfg(feature = "encoding")]
+    use super::*;
+
+    #[test]
+    #[cfg(feature = "encoding")]
+    fn allowed_matches_namespaces() {
+        let namespaces = vec!["user".to_string(), "security.capability".to_string()];
+
+        assert!(allowed("user.comment", &namespaces));
+        assert!(allowed("security.capability", &namespaces));
+        assert!(!allowed("security.selinux", &namespaces));
+        assert!(!allowed("username.comment", &namespaces));
+        assert!(!allowed("trusted.overlay", &namespaces));
+    }
+
+    #[test]
+    #[cfg(all(feature = "encoding", feature = "decoding"))]
+    fn read_returns_applied() {
+        let path = std::env::temp_dir().join("lcas_xattrs_test");
+        std::fs::write(&path, b"").unwrap();
+
+        let xattrs = Xattrs::from([
+            ("user.a".to_string(), vec![1, 2, 3]),
+            ("user.b".to_string(), vec![]),
+        ]);
+        apply(std::fs::File::open(&path).unwrap(), xattrs.iter()).unwrap();
+
+        assert_eq!(read(&path, &["user".to_string()]).unwrap(), xattrs);
+        assert!(read(&path, &[]).unwrap().is_empty());
+
+        let _ = std::fs::remove_file(&path);
+    }
+}